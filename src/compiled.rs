@@ -0,0 +1,811 @@
+use std::borrow::Cow;
+use std::collections::HashMap;
+use std::collections::HashSet;
+use std::rc::Rc;
+use std::sync::OnceLock;
+use ion_rs::{IonType, Symbol};
+use ion_rs::element::{Annotations, Element};
+use num::ToPrimitive;
+use regex::{Regex, RegexBuilder};
+use unicode_normalization::{is_nfc, UnicodeNormalization};
+use wildmatch::WildMatch;
+use crate::{AnnotationKey, Axis, CompareOp, EvalContext, EvalError, EvalOptions, Key, Literal, Location, NameMatching, Path, Predicate, PredicateExpr, Segment, Strictness};
+use crate::expr::{CompiledExpr, Operand};
+use crate::location::Node;
+use crate::trace::{SegmentEvent, Tracer};
+
+
+/// A [`Path`] prepared for repeated evaluation.
+///
+/// Compiling builds the field name matchers and annotation symbols of every segment (and of
+/// every predicate subpath) once, so evaluating the same query against many documents has no
+/// per-document setup cost. Every `Path` evaluation method compiles the path first; keep a
+/// `CompiledPath` around instead when the same query runs more than once.
+#[derive(Debug, Clone)]
+pub struct CompiledPath {
+    pub(crate) absolute: bool,
+    pub(crate) segments: Vec<CompiledSegment>,
+}
+
+impl CompiledPath {
+    pub fn new(path: &Path) -> Self {
+        CompiledPath {
+            absolute: path.absolute,
+            segments: path.segments.iter().map(CompiledSegment::new).collect(),
+        }
+    }
+
+    pub fn match_element(&self, root_element: Element) -> Vec<Element> {
+        self.match_ref(&root_element).into_iter().cloned().collect()
+    }
+
+    /// like [`CompiledPath::match_element`], but borrows the root element and returns
+    /// references to the matched elements instead of cloning them.
+    pub fn match_ref<'a>(&self, root_element: &'a Element) -> Vec<&'a Element> {
+        self.match_located(root_element).into_iter().map(|(_, element)| element).collect()
+    }
+
+    /// like [`CompiledPath::match_ref`], but also returns where in `root_element` each match
+    /// was found.
+    pub fn match_located<'a>(&self, root_element: &'a Element) -> Vec<(Location, &'a Element)> {
+        let ctx = EvalContext::new(std::slice::from_ref(root_element));
+        // evaluating with the default options never fails
+        self.evaluate(&ctx, vec![Node::root(root_element, 0)]).unwrap_or_default().into_iter()
+            .map(|node| (node.location(), node.element))
+            .collect()
+    }
+
+    /// evaluates this path starting at `element`, or at the context's root(s) if the path
+    /// is absolute. the context's options are ignored, see [`CompiledPath::try_match_element_in`].
+    pub fn match_element_in<'a>(&self, ctx: &EvalContext<'a>, element: &'a Element) -> Vec<&'a Element> {
+        self.try_match_element_in(&ctx.lenient(), element).unwrap_or_default()
+    }
+
+    /// like [`CompiledPath::match_element`], evaluating with the given options.
+    pub fn try_match_element(&self, root_element: Element, options: EvalOptions) -> Result<Vec<Element>, EvalError> {
+        let ctx = EvalContext::new(std::slice::from_ref(&root_element)).with_options(options);
+        Ok(self.try_match_element_in(&ctx, &root_element)?.into_iter().cloned().collect())
+    }
+
+    /// like [`CompiledPath::match_element_in`], evaluating with the context's options.
+    pub fn try_match_element_in<'a>(&self, ctx: &EvalContext<'a>, element: &'a Element) -> Result<Vec<&'a Element>, EvalError> {
+        Ok(self.evaluate(ctx, self.start_nodes(ctx, &Node::root(element, 0)))?.into_iter()
+            .map(|node| node.element)
+            .collect())
+    }
+
+    /// the nodes evaluation starts from when this path is evaluated at `node`.
+    pub(crate) fn start_nodes<'a>(&self, ctx: &EvalContext<'a>, node: &Node<'a>) -> Vec<Node<'a>> {
+        if self.absolute {
+            ctx.roots().iter().enumerate().map(|(i, root)| Node::root(root, i)).collect()
+        }
+        else {
+            vec![node.clone()]
+        }
+    }
+
+    /// like [`CompiledPath::match_ref`], reporting what happens at every segment to `tracer`.
+    pub fn match_traced<'a>(&self, root_element: &'a Element, tracer: &mut dyn Tracer) -> Vec<&'a Element> {
+        let ctx = EvalContext::new(std::slice::from_ref(root_element));
+        // evaluating with the default options never fails
+        self.evaluate_traced(&ctx, vec![Node::root(root_element, 0)], Some(tracer)).unwrap_or_default().into_iter()
+            .map(|node| node.element)
+            .collect()
+    }
+
+    /// like [`CompiledPath::try_match_element_in`], reporting what happens at every segment to
+    /// `tracer`.
+    pub fn try_match_traced_in<'a>(&self, ctx: &EvalContext<'a>, element: &'a Element, tracer: &mut dyn Tracer) -> Result<Vec<&'a Element>, EvalError> {
+        Ok(self.evaluate_traced(ctx, self.start_nodes(ctx, &Node::root(element, 0)), Some(tracer))?.into_iter()
+            .map(|node| node.element)
+            .collect())
+    }
+
+    /// runs every segment of this path, starting with the given context nodes.
+    pub(crate) fn evaluate<'a>(&self, ctx: &EvalContext<'a>, context: Vec<Node<'a>>) -> Result<Vec<Node<'a>>, EvalError> {
+        self.evaluate_traced(ctx, context, None)
+    }
+
+    fn evaluate_traced<'a>(&self, ctx: &EvalContext<'a>, context: Vec<Node<'a>>, tracer: Option<&mut dyn Tracer>) -> Result<Vec<Node<'a>>, EvalError> {
+        let found = run_segments(ctx, &self.segments, context, tracer)?;
+        ctx.add_results(found.len())?;
+        Ok(found)
+    }
+}
+
+/// runs `segments` starting with the given context nodes, without counting the nodes it ends
+/// with as results.
+///
+/// a segment that can reach an element from more than one context node (see [`reaches_once`])
+/// reaches it only once, and the context is put back in document order after it.
+pub(crate) fn run_segments<'a>(ctx: &EvalContext<'a>, segments: &[CompiledSegment], mut context: Vec<Node<'a>>, mut tracer: Option<&mut dyn Tracer>) -> Result<Vec<Node<'a>>, EvalError> {
+    for (index, seg) in segments.iter().enumerate() {
+        let mut event = tracer.as_ref().map(|_| SegmentEvent {
+            index,
+            segment: &seg.segment,
+            input: context.len(),
+            candidates: 0,
+            filtered_by_annotations: Vec::new(),
+            filtered_by_type: Vec::new(),
+            filtered_by_predicates: vec![Vec::new(); seg.predicates.len()],
+            output: 0,
+        });
+        let mut next_context = Vec::new();
+        {
+            let mut matches = seg.key_matches(ctx, context);
+            while let Some(candidate) = matches.next(&seg.key, ctx)? {
+                let annotations_match = seg.match_annotations(candidate.element.annotations(), seg.name_matching(ctx.options()));
+                let type_matches = annotations_match && seg.match_type(candidate.element.ion_type());
+                let failed_pred = if type_matches { seg.failed_predicate(ctx, &candidate)? } else { None };
+                if let Some(event) = event.as_mut() {
+                    event.candidates += 1;
+                    if !annotations_match {
+                        event.filtered_by_annotations.push((candidate.location(), candidate.element));
+                    }
+                    else if !type_matches {
+                        event.filtered_by_type.push((candidate.location(), candidate.element));
+                    }
+                    else if let Some(pred) = failed_pred {
+                        event.filtered_by_predicates[pred].push((candidate.location(), candidate.element));
+                    }
+                }
+                if type_matches && failed_pred.is_none() {
+                    next_context.push(candidate);
+                    ctx.check_context_size(next_context.len())?;
+                }
+            }
+        }
+        if reaches_once(segments, index) {
+            Node::document_order(&mut next_context);
+        }
+        if let (Some(tracer), Some(mut event)) = (tracer.as_mut(), event) {
+            event.output = next_context.len();
+            tracer.segment(&event);
+        }
+        context = next_context;
+    }
+    Ok(context)
+}
+
+/// true if `segments[k]` can reach the same element from several context nodes: segments that
+/// don't move to children (e.g. `..` from every matching child), and recursive segments once the
+/// context can hold both an element and one of its descendants (e.g. the second `//` in `//n//x`).
+pub(crate) fn reaches_once(segments: &[CompiledSegment], k: usize) -> bool {
+    segments[k].axis != Axis::Child
+        || segments[k].recursive && segments[..k].iter().any(|seg| seg.recursive || seg.axis != Axis::Child)
+}
+
+
+#[derive(Debug, Clone)]
+pub(crate) struct CompiledSegment {
+    /// the segment this was compiled from.
+    pub segment: Segment,
+    pub recursive: bool,
+    pub axis: Axis,
+    pub key: KeyMatcher,
+    pub annotation_lists: Vec<Vec<AnnotationMatcher>>,
+    pub predicates: Vec<CompiledPredicateExpr>,
+}
+
+impl CompiledSegment {
+    pub fn new(seg: &Segment) -> Self {
+        CompiledSegment {
+            segment: seg.clone(),
+            recursive: seg.recursive,
+            axis: seg.axis,
+            key: KeyMatcher::new(&seg.key),
+            annotation_lists: seg.annotation_lists.iter()
+                .map(|list| list.iter().map(AnnotationMatcher::new).collect())
+                .collect(),
+            predicates: seg.predicates.iter().map(CompiledPredicateExpr::new).collect(),
+        }
+    }
+
+    /// how this segment compares names when evaluated with `options`.
+    pub fn name_matching(&self, options: &EvalOptions) -> NameMatching {
+        self.segment.name_matching.union(options.name_matching)
+    }
+
+    /// the nodes this segment moves to from `starts` that match its key.
+    pub fn key_matches<'a>(&self, ctx: &EvalContext<'a>, starts: Vec<Node<'a>>) -> KeyMatches<'a> {
+        KeyMatches::new(&self.key, self.axis, self.recursive, self.name_matching(ctx.options()), starts)
+    }
+
+    pub fn match_annotations(&self, annotations: &Annotations, matching: NameMatching) -> bool {
+        // must match ALL lists, where each list must match ANY annotation
+        self.annotation_lists.iter()
+            .all(|list| annotations.iter().any(|ann| list.iter().any(|option| option.matches(ann, matching))))
+    }
+
+    pub fn match_type(&self, ty: IonType) -> bool {
+        self.segment.types.is_empty() || self.segment.types.contains(&ty)
+    }
+
+    pub fn match_predicates<'a>(&self, ctx: &EvalContext<'a>, node: &Node<'a>) -> Result<bool, EvalError> {
+        Ok(self.failed_predicate(ctx, node)?.is_none())
+    }
+
+    /// the index of the first predicate (`[...]`) `node` doesn't match, if any.
+    pub fn failed_predicate<'a>(&self, ctx: &EvalContext<'a>, node: &Node<'a>) -> Result<Option<usize>, EvalError> {
+        for (i, pred) in self.predicates.iter().enumerate() {
+            if !pred.filter_node(ctx, node)? {
+                return Ok(Some(i));
+            }
+        }
+        Ok(None)
+    }
+}
+
+
+/// An [`AnnotationKey`], prepared for matching.
+#[derive(Debug, Clone)]
+pub(crate) enum AnnotationMatcher {
+    Name(Folded<String>),
+    /// `None` if the regular expression is invalid, which never matches.
+    Regex(Folded<Option<Regex>>),
+}
+
+impl AnnotationMatcher {
+    pub fn new(key: &AnnotationKey) -> Self {
+        match key {
+            AnnotationKey::Name(name) => AnnotationMatcher::Name(Folded::new(name, fold_name)),
+            AnnotationKey::Regex(pattern) => AnnotationMatcher::Regex(Folded::new(pattern, build_regex)),
+        }
+    }
+
+    pub fn matches(&self, annotation: &Symbol, matching: NameMatching) -> bool {
+        let text = match annotation.text() {
+            Some(text) => text,
+            None => return false,
+        };
+        match self {
+            AnnotationMatcher::Name(name) if matching.is_exact() => name.source == text,
+            AnnotationMatcher::Name(name) => *name.get(matching) == fold(text, matching),
+            AnnotationMatcher::Regex(regex) => match regex.get(matching) {
+                // the regular expression itself ignores case
+                Some(regex) => regex.is_match(&fold(text, NameMatching { ignore_case: false, ..matching })),
+                None => false,
+            },
+        }
+    }
+}
+
+
+/// A [`PredicateExpr`], prepared for evaluation.
+#[derive(Debug, Clone)]
+pub(crate) enum CompiledPredicateExpr {
+    Predicate(CompiledPredicate),
+    And(Vec<CompiledPredicateExpr>),
+    Or(Vec<CompiledPredicateExpr>),
+    Not(Box<CompiledPredicateExpr>),
+}
+
+impl CompiledPredicateExpr {
+    pub fn new(expr: &PredicateExpr) -> Self {
+        match expr {
+            PredicateExpr::Predicate(pred) => CompiledPredicateExpr::Predicate(CompiledPredicate::new(pred)),
+            PredicateExpr::And(exprs) => CompiledPredicateExpr::And(exprs.iter().map(CompiledPredicateExpr::new).collect()),
+            PredicateExpr::Or(exprs) => CompiledPredicateExpr::Or(exprs.iter().map(CompiledPredicateExpr::new).collect()),
+            PredicateExpr::Not(expr) => CompiledPredicateExpr::Not(Box::new(CompiledPredicateExpr::new(expr))),
+        }
+    }
+
+    /// stops at the first operand that decides the result.
+    pub fn filter_node<'a>(&self, ctx: &EvalContext<'a>, node: &Node<'a>) -> Result<bool, EvalError> {
+        match self {
+            CompiledPredicateExpr::Predicate(pred) => pred.filter_node(ctx, node),
+            CompiledPredicateExpr::And(exprs) => {
+                for expr in exprs.iter() {
+                    if !expr.filter_node(ctx, node)? {
+                        return Ok(false);
+                    }
+                }
+                Ok(true)
+            },
+            CompiledPredicateExpr::Or(exprs) => {
+                for expr in exprs.iter() {
+                    if expr.filter_node(ctx, node)? {
+                        return Ok(true);
+                    }
+                }
+                Ok(false)
+            },
+            CompiledPredicateExpr::Not(expr) => Ok(!expr.filter_node(ctx, node)?),
+        }
+    }
+
+    /// all predicates in the expression.
+    pub fn predicates(&self) -> Vec<&CompiledPredicate> {
+        match self {
+            CompiledPredicateExpr::Predicate(pred) => vec![pred],
+            CompiledPredicateExpr::And(exprs) | CompiledPredicateExpr::Or(exprs) =>
+                exprs.iter().flat_map(CompiledPredicateExpr::predicates).collect(),
+            CompiledPredicateExpr::Not(expr) => expr.predicates(),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub(crate) enum CompiledPredicate {
+    Path(CompiledPath),
+    Compare {
+        path: Option<CompiledPath>,
+        op: CompareOp,
+        value: Literal,
+    },
+    Condition {
+        lhs: CompiledExpr,
+        op: CompareOp,
+        rhs: CompiledExpr,
+    },
+    /// a function call, which has to return `true`.
+    Test(CompiledExpr),
+}
+
+impl CompiledPredicate {
+    pub fn new(pred: &Predicate) -> Self {
+        match pred {
+            Predicate::Path(path) => CompiledPredicate::Path(path.compile()),
+            Predicate::Compare { path, op, value } => CompiledPredicate::Compare {
+                path: path.as_ref().map(|p| p.compile()),
+                op: op.clone(),
+                value: value.clone(),
+            },
+            Predicate::Condition { lhs, op, rhs } => CompiledPredicate::Condition {
+                lhs: CompiledExpr::new(lhs),
+                op: op.clone(),
+                rhs: CompiledExpr::new(rhs),
+            },
+            Predicate::Call(function, args) => CompiledPredicate::Test(
+                CompiledExpr::Call(*function, args.iter().map(CompiledExpr::new).collect())),
+        }
+    }
+
+    pub fn filter_node<'a>(&self, ctx: &EvalContext<'a>, node: &Node<'a>) -> Result<bool, EvalError> {
+        ctx.nested(|| self.filter_nested(ctx, node))
+    }
+
+    fn filter_nested<'a>(&self, ctx: &EvalContext<'a>, node: &Node<'a>) -> Result<bool, EvalError> {
+        match self {
+            CompiledPredicate::Path(path) => {
+                Ok(path.iter_node(ctx, node).try_next_node()?.is_some())
+            }
+            CompiledPredicate::Compare { path: None, op, value } => {
+                compare(ctx, node, op, value)
+            }
+            CompiledPredicate::Compare { path: Some(p), op, value } => {
+                let mut subquery_res = p.iter_node(ctx, node);
+                while let Some(sub) = subquery_res.try_next_node()? {
+                    if compare(ctx, &sub, op, value)? {
+                        return Ok(true);
+                    }
+                }
+                Ok(false)
+            }
+            CompiledPredicate::Condition { lhs, op, rhs } => {
+                let (lhs, rhs) = (lhs.evaluate(ctx, node)?, rhs.evaluate(ctx, node)?);
+                for a in lhs.iter() {
+                    for b in rhs.iter() {
+                        ctx.add_values(1)?;
+                        let holds = match (a, b) {
+                            (Operand::Value(a), Operand::Value(b)) => op.test(a, b),
+                            (Operand::Container(node), Operand::Value(value)) => compare(ctx, node, op, value)?,
+                            (Operand::Value(value), Operand::Container(node)) => compare(ctx, node, &op.flipped(), value)?,
+                            // there's no value to report
+                            (Operand::Container(_), Operand::Container(_)) => false,
+                        };
+                        if holds {
+                            return Ok(true);
+                        }
+                    }
+                }
+                Ok(false)
+            }
+            CompiledPredicate::Test(expr) => {
+                let values = expr.evaluate(ctx, node)?;
+                Ok(values.iter().any(|value| matches!(value, Operand::Value(Literal::Boolean(true)))))
+            }
+        }
+    }
+}
+
+/// checks `node <op> value`. structs, lists and sexps never match, or are an error in strict mode.
+fn compare<'a>(ctx: &EvalContext<'a>, node: &Node<'a>, op: &CompareOp, value: &Literal) -> Result<bool, EvalError> {
+    match Literal::try_from(node.element.value().clone()) {
+        Ok(lit) => Ok(op.test(&lit, value)),
+        Err(()) => match ctx.options().strictness {
+            Strictness::Lenient => Ok(false),
+            Strictness::Strict => Err(EvalError::NonScalarComparison {
+                found: node.element.ion_type(),
+                op: op.clone(),
+                value: value.clone(),
+                location: node.location(),
+            }),
+        }
+    }
+}
+
+
+/// A segment's key, prepared for matching.
+#[derive(Debug, Clone)]
+pub(crate) enum KeyMatcher {
+    /// `*`, matches every field and every sequence item.
+    Any,
+    /// an exact field name.
+    Name(Folded<String>),
+    /// a field name with `*` or `?` wildcards.
+    Glob(Folded<WildMatch>),
+    /// `None` if the regular expression is invalid, which never matches.
+    Regex(Folded<Option<Regex>>),
+    /// a sequence index. `None` if the index doesn't fit in an `i32`, which never matches.
+    Index(Option<i32>),
+    Slice(Option<i32>, Option<i32>, Option<i32>),
+    /// matches whatever any of the keys matches. sequence items are selected in document
+    /// order, each only once.
+    Choice(Vec<KeyMatcher>),
+}
+
+impl KeyMatcher {
+    pub fn new(key: &Key) -> Self {
+        match key {
+            Key::String(s) => KeyMatcher::Name(Folded::new(s, fold_name)),
+            Key::Symbol(s) => {
+                if s.as_str() == "*" { KeyMatcher::Any }
+                else if key.is_pattern() { KeyMatcher::Glob(Folded::new(s, |s, matching| WildMatch::new(&fold(s, matching)))) }
+                else { KeyMatcher::Name(Folded::new(s, fold_name)) }
+            },
+            Key::Index(i) => KeyMatcher::Index(i.to_i32()),
+            Key::Slice(start, end, step) => KeyMatcher::Slice(*start, *end, *step),
+            Key::Choice(keys) => KeyMatcher::Choice(keys.iter().map(KeyMatcher::new).collect()),
+            Key::Regex(pattern) => KeyMatcher::Regex(Folded::new(pattern, build_regex)),
+        }
+    }
+
+    /// true if a struct field called `name` matches this key.
+    pub fn matches_field_name(&self, name: &str, matching: NameMatching) -> bool {
+        match self {
+            KeyMatcher::Any => true,
+            KeyMatcher::Name(s) if matching.is_exact() => s.source == name,
+            KeyMatcher::Name(s) => *s.get(matching) == fold(name, matching),
+            KeyMatcher::Glob(glob) => glob.get(matching).matches(&fold(name, matching)),
+            KeyMatcher::Regex(regex) => match regex.get(matching) {
+                // the regular expression itself ignores case
+                Some(regex) => regex.is_match(&fold(name, NameMatching { ignore_case: false, ..matching })),
+                None => false,
+            },
+            KeyMatcher::Choice(keys) => keys.iter().any(|key| key.matches_field_name(name, matching)),
+            _ => false
+        }
+    }
+
+    /// indices of the items selected by this key in a sequence of length `len`, in the order
+    /// they are selected.
+    pub fn sequence_indices(&self, len: usize) -> Vec<usize> {
+        if len == 0 { return Vec::new(); }
+        match self {
+            KeyMatcher::Index(i) => {
+                if let Some(small) = *i {
+                    let small = wrap_index(small, len);
+                    if small < len as i64 {
+                        return vec![small as usize];
+                    }
+                }
+            },
+            KeyMatcher::Slice(start, end, step) => {
+                // len is known to be > 0 at this point
+                let last = len as i64 - 1;
+                let start = start.map_or(0, |start| wrap_index(start, len));
+                let end = end.map_or(last, |end| wrap_index(end, len));
+                let step = step.unwrap_or(1) as i64;
+                if start == end {
+                    return if start <= last { vec![start as usize] } else { vec![] };
+                }
+                // only the indices up to the last item are visited, however far the bounds are
+                if step > 0 && end > start && start <= last {
+                    return (start..=end.min(last)).step_by(step as usize).map(|i| i as usize).collect();
+                }
+                if step < 0 && start > end {
+                    // the first index the step reaches from `start` that is an item
+                    let skipped = (start - last).max(0);
+                    let first = start - (skipped + -step - 1) / -step * -step;
+                    if first >= end {
+                        return (end..=first).rev().step_by(-step as usize).map(|i| i as usize).collect();
+                    }
+                }
+                // a step of 0, or one away from the end, selects nothing
+                return Vec::new();
+            },
+            KeyMatcher::Any => return (0..len).collect(),
+            KeyMatcher::Choice(keys) => {
+                let mut selected = vec![false; len];
+                for i in keys.iter().flat_map(|key| key.sequence_indices(len)) {
+                    selected[i] = true;
+                }
+                return (0..len).filter(|&i| selected[i]).collect();
+            },
+            // field names never match sequence elements
+            KeyMatcher::Name(_) | KeyMatcher::Glob(_) | KeyMatcher::Regex(_) => {}
+        }
+        Vec::new()
+    }
+
+    /// whether the item at index `i` of a sequence matches this key, if that can be decided
+    /// without knowing the length of the sequence. keys that count from the end of the
+    /// sequence or select items in reverse order return `None`.
+    pub fn index_matches(&self, i: usize) -> Option<bool> {
+        let i = i as i64;
+        match self {
+            KeyMatcher::Index(n) => {
+                match *n {
+                    Some(n) if n >= 0 => Some(i == n as i64),
+                    Some(_) => None,
+                    None => Some(false)
+                }
+            },
+            KeyMatcher::Slice(start, end, step) => {
+                let start = start.unwrap_or(0) as i64;
+                let step = step.unwrap_or(1) as i64;
+                if start < 0 || step < 0 { return None; }
+                match end {
+                    Some(end) if *end < 0 => None,
+                    Some(end) => {
+                        let end = *end as i64;
+                        // mirrors sequence_indices(): a single item if start == end, regardless of step
+                        if start == end { Some(i == start) }
+                        else { Some(step > 0 && end > start && i >= start && i <= end && (i - start) % step == 0) }
+                    },
+                    // with a step of 0 only a start at the very last item selects anything
+                    None if step == 0 => None,
+                    // an open end runs up to the last item, however long the sequence is
+                    None => Some(i >= start && (i - start) % step == 0),
+                }
+            },
+            KeyMatcher::Any => Some(true),
+            KeyMatcher::Name(_) | KeyMatcher::Glob(_) | KeyMatcher::Regex(_) => Some(false),
+            // undecidable if any of the keys is, no matter the index
+            KeyMatcher::Choice(keys) => keys.iter()
+                .map(|key| key.index_matches(i as usize))
+                .try_fold(false, |any, matched| Some(matched? || any)),
+        }
+    }
+
+    /// whether `node` matches this key, judging by its field name or by its position in the
+    /// parent sequence. an element without a parent only matches `*`.
+    pub fn matches_node(&self, node: &Node, matching: NameMatching) -> bool {
+        match (&node.parent, node.field) {
+            (_, Some(name)) => self.matches_field_name(name, matching),
+            (Some(parent), None) => match parent.element.as_sequence() {
+                Some(sequence) => self.sequence_indices(sequence.len()).contains(&node.position),
+                None => false,
+            },
+            (None, None) => matches!(self, KeyMatcher::Any),
+        }
+    }
+
+    /// the nodes a step along an axis other than `Child` moves to from any of `starts`, each
+    /// paired with whether it matches the key. every element is included only once, and each
+    /// one is only reached once as well: walking up stops at the first ancestor that was
+    /// already reached, and only the first (or last) start in each parent has its siblings
+    /// collected, since they include those of all others.
+    fn axis_candidates<'a>(&self, starts: Vec<Node<'a>>, axis: Axis, matching: NameMatching) -> Vec<(Node<'a>, bool)> {
+        let mut found = Vec::new();
+        match axis {
+            Axis::Child => unreachable!("child steps have their own candidates"),
+            Axis::Parent | Axis::Ancestor => {
+                let mut seen = HashSet::new();
+                for node in starts {
+                    for ancestor in std::iter::successors(node.parent.clone(), |parent| parent.parent.clone()) {
+                        if !seen.insert(ancestor.element as *const Element) {
+                            // and so were all of its ancestors
+                            break;
+                        }
+                        found.push(((*ancestor).clone(), self.matches_node(&ancestor, matching)));
+                        if axis == Axis::Parent {
+                            break;
+                        }
+                    }
+                }
+            },
+            Axis::FollowingSibling | Axis::PrecedingSibling => {
+                let following = axis == Axis::FollowingSibling;
+                let mut nearest: Vec<Node<'a>> = Vec::new();
+                let mut by_parent: HashMap<*const Element, usize> = HashMap::new();
+                for node in starts {
+                    let parent = match node.parent.as_ref() {
+                        Some(parent) => parent.element as *const Element,
+                        None => continue,
+                    };
+                    match by_parent.get(&parent) {
+                        Some(&i) => if (node.position < nearest[i].position) == following {
+                            nearest[i] = node;
+                        },
+                        None => {
+                            by_parent.insert(parent, nearest.len());
+                            nearest.push(node);
+                        },
+                    }
+                }
+                for node in nearest {
+                    let parent = node.parent.as_ref().expect("only nodes with a parent are kept");
+                    found.extend(self.all_children(parent, matching).into_iter()
+                        .filter(|(sibling, _)| if following { sibling.position > node.position } else { sibling.position < node.position }));
+                }
+            },
+        }
+        found
+    }
+
+    /// the children of `parent` that have to be considered, in the order they should be
+    /// visited, each paired with whether it matches the key. when searching recursively this
+    /// is every child in document order (so that their descendants can be searched as well);
+    /// otherwise only the matches are included.
+    fn candidates<'a>(&self, parent: &Rc<Node<'a>>, recursive: bool, matching: NameMatching) -> Vec<(Node<'a>, bool)> {
+        if recursive {
+            self.all_children(parent, matching)
+        }
+        else {
+            self.matched_children(parent, matching).into_iter().map(|node| (node, true)).collect()
+        }
+    }
+
+    /// every child of `parent` in document order, paired with whether it matches the key.
+    fn all_children<'a>(&self, parent: &Rc<Node<'a>>, matching: NameMatching) -> Vec<(Node<'a>, bool)> {
+        if let Some(sequence) = parent.element.as_sequence() {
+            let indices = self.sequence_indices(sequence.len());
+            sequence.elements().enumerate()
+                .map(|(i, child)| (Node::child(parent, child, i, None), indices.contains(&i)))
+                .collect()
+        }
+        else if let Some(st) = parent.element.as_struct() {
+            st.fields().enumerate()
+                .map(|(i, (name, child))| {
+                    let name = name.text().unwrap_or("$0");
+                    (Node::child(parent, child, i, Some(name)), self.matches_field_name(name, matching))
+                })
+                .collect()
+        }
+        else {
+            Vec::new()
+        }
+    }
+
+    /// the children of `parent` that match the key, in the order the key selects them.
+    pub fn matched_children<'a>(&self, parent: &Rc<Node<'a>>, matching: NameMatching) -> Vec<Node<'a>> {
+        if let Some(sequence) = parent.element.as_sequence() {
+            self.sequence_indices(sequence.len()).into_iter()
+                .filter_map(|i| sequence.get(i).map(|child| Node::child(parent, child, i, None)))
+                .collect()
+        }
+        else if let Some(st) = parent.element.as_struct() {
+            if let KeyMatcher::Name(name) = self {
+                // an exact name is looked up instead of compared with every field. the struct
+                // doesn't say where the fields it returns are, but returns them in order, so
+                // their positions are found by walking the fields only up to the last of them,
+                // comparing addresses. fields without text are only ever matched by their
+                // placeholder name `$0`.
+                if matching.is_exact() && name.source != "$0" {
+                    let mut fields = st.fields().enumerate();
+                    return st.get_all(name.source.as_str())
+                        .filter_map(|child| fields.find(|(_, (_, field))| std::ptr::eq(*field, child)))
+                        .map(|(i, (field, child))| Node::child(parent, child, i, field.text()))
+                        .collect();
+                }
+            }
+            st.fields().enumerate()
+                .map(|(i, (name, child))| (i, name.text().unwrap_or("$0"), child))
+                .filter(|(_, name, _)| self.matches_field_name(name, matching))
+                .map(|(i, name, child)| Node::child(parent, child, i, Some(name)))
+                .collect()
+        }
+        else {
+            Vec::new()
+        }
+    }
+}
+
+
+/// A name or pattern from a query, along with the forms it takes under each [`NameMatching`].
+/// each form is only built the first time it's needed.
+#[derive(Debug, Clone)]
+pub(crate) struct Folded<T> {
+    pub source: String,
+    build: fn(&str, NameMatching) -> T,
+    forms: [OnceLock<T>; 4],
+}
+
+impl<T> Folded<T> {
+    fn new(source: &str, build: fn(&str, NameMatching) -> T) -> Self {
+        Folded { source: source.to_string(), build, forms: Default::default() }
+    }
+
+    fn get(&self, matching: NameMatching) -> &T {
+        let i = usize::from(matching.ignore_case) | usize::from(matching.normalize) << 1;
+        self.forms[i].get_or_init(|| (self.build)(&self.source, matching))
+    }
+}
+
+/// a negative index from a key counted from the end of a sequence of length `len`, wrapping
+/// around as many times as needed.
+fn wrap_index(index: i32, len: usize) -> i64 {
+    match index {
+        index if index < 0 => (index as i64).rem_euclid(len as i64),
+        index => index as i64,
+    }
+}
+
+/// `name` in the form it's compared in under `matching`.
+fn fold(name: &str, matching: NameMatching) -> Cow<'_, str> {
+    let mut name = Cow::Borrowed(name);
+    if matching.normalize && !is_nfc(&name) {
+        name = Cow::Owned(name.nfc().collect());
+    }
+    if matching.ignore_case {
+        name = Cow::Owned(name.to_lowercase());
+    }
+    name
+}
+
+fn fold_name(name: &str, matching: NameMatching) -> String {
+    fold(name, matching).into_owned()
+}
+
+/// `None` if `pattern` isn't a valid regular expression. the expression is anchored, so that
+/// it only matches whole names.
+fn build_regex(pattern: &str, matching: NameMatching) -> Option<Regex> {
+    let pattern = fold(pattern, NameMatching { ignore_case: false, ..matching });
+    // an invalid pattern like `a)|(b` would otherwise close the group it's wrapped in
+    Regex::new(&pattern).ok()?;
+    RegexBuilder::new(&format!("^(?:{})$", pattern))
+        .case_insensitive(matching.ignore_case)
+        .build()
+        .ok()
+}
+
+
+/// Nodes that still have to be checked against a key, for a set of starting nodes.
+///
+/// When searching recursively, the children of each child are visited right after it,
+/// which yields matches in document order.
+pub(crate) struct KeyMatches<'a> {
+    recursive: bool,
+    matching: NameMatching,
+    /// a stack of partially visited containers.
+    levels: Vec<std::vec::IntoIter<(Node<'a>, bool)>>,
+}
+
+impl<'a> KeyMatches<'a> {
+    pub fn new(key: &KeyMatcher, axis: Axis, recursive: bool, matching: NameMatching, starts: Vec<Node<'a>>) -> Self {
+        // only child steps search recursively
+        let recursive = recursive && axis == Axis::Child;
+        KeyMatches {
+            recursive,
+            matching,
+            levels: match axis {
+                Axis::Child => starts.into_iter().rev()
+                    .map(|node| key.candidates(&Rc::new(node), recursive, matching).into_iter())
+                    .collect(),
+                _ => vec![key.axis_candidates(starts, axis, matching).into_iter()],
+            },
+        }
+    }
+
+    /// the next node matching the key, if any. every node reached counts as visited in `ctx`.
+    pub fn next(&mut self, key: &KeyMatcher, ctx: &EvalContext) -> Result<Option<Node<'a>>, EvalError> {
+        while let Some(level) = self.levels.last_mut() {
+            match level.next() {
+                None => { self.levels.pop(); },
+                Some((child, matched)) => {
+                    ctx.visit(&child)?;
+                    if !self.recursive {
+                        if matched { return Ok(Some(child)); }
+                        continue;
+                    }
+                    let child = Rc::new(child);
+                    self.levels.push(key.candidates(&child, true, self.matching).into_iter());
+                    if matched { return Ok(Some((*child).clone())); }
+                }
+            }
+        }
+        Ok(None)
+    }
+}
@@ -0,0 +1,113 @@
+use std::cell::Cell;
+use std::rc::Rc;
+use ion_rs::element::Element;
+use crate::{EvalError, EvalOptions, Limit};
+use crate::location::Node;
+
+
+/// State shared by every step of a single evaluation, including nested predicate subqueries.
+///
+/// Carries the root element(s) of the document so that absolute paths (`/a/b`) always start
+/// from the top of the document, even when they appear inside a predicate.
+///
+/// The context also keeps track of the work done so far, to enforce the limits of its
+/// [`EvalOptions`]. A context and its clones share these counts, so every evaluation that
+/// uses them counts towards the same limits.
+#[derive(Debug, Clone)]
+pub struct EvalContext<'r> {
+    roots: &'r [Element],
+    options: EvalOptions,
+    usage: Rc<Usage>,
+}
+
+/// Work done by the evaluations sharing a context.
+#[derive(Debug, Default)]
+struct Usage {
+    visited: Cell<usize>,
+    results: Cell<usize>,
+    values: Cell<usize>,
+    /// how many predicates are currently being evaluated inside each other.
+    nesting: Cell<usize>,
+}
+
+impl<'r> EvalContext<'r> {
+    /// a context for evaluating against the given top-level element(s).
+    pub fn new(roots: &'r [Element]) -> Self {
+        EvalContext { roots, options: EvalOptions::default(), usage: Rc::default() }
+    }
+
+    pub fn with_options(mut self, options: EvalOptions) -> Self {
+        self.options = options;
+        self
+    }
+
+    /// the top-level element(s) absolute paths are evaluated against.
+    pub fn roots(&self) -> &'r [Element] {
+        self.roots
+    }
+
+    pub fn options(&self) -> &EvalOptions {
+        &self.options
+    }
+
+    /// the number of elements reached so far.
+    pub fn visited(&self) -> usize {
+        self.usage.visited.get()
+    }
+
+    /// the same options and counts, evaluating against other roots.
+    pub(crate) fn with_roots<'s>(&self, roots: &'s [Element]) -> EvalContext<'s> {
+        EvalContext { roots, options: self.options, usage: self.usage.clone() }
+    }
+
+    /// the same roots, with default options.
+    pub(crate) fn lenient(&self) -> Self {
+        EvalContext::new(self.roots)
+    }
+
+    /// counts `node` as visited.
+    pub(crate) fn visit(&self, node: &Node) -> Result<(), EvalError> {
+        check(Limit::Depth, self.options.max_depth, node.depth)?;
+        let visited = self.usage.visited.get() + 1;
+        check(Limit::Visited, self.options.max_visited, visited)?;
+        self.usage.visited.set(visited);
+        Ok(())
+    }
+
+    /// counts `n` more results.
+    pub(crate) fn add_results(&self, n: usize) -> Result<(), EvalError> {
+        let results = self.usage.results.get() + n;
+        check(Limit::Results, self.options.max_results, results)?;
+        self.usage.results.set(results);
+        Ok(())
+    }
+
+    /// counts `n` more values computed by predicates.
+    pub(crate) fn add_values(&self, n: usize) -> Result<(), EvalError> {
+        let values = self.usage.values.get().saturating_add(n);
+        check(Limit::Values, self.options.max_values, values)?;
+        self.usage.values.set(values);
+        Ok(())
+    }
+
+    pub(crate) fn check_context_size(&self, size: usize) -> Result<(), EvalError> {
+        check(Limit::ContextSize, self.options.max_context_size, size)
+    }
+
+    /// runs `f` as a predicate nested inside any predicates currently being evaluated.
+    pub(crate) fn nested<T>(&self, f: impl FnOnce() -> Result<T, EvalError>) -> Result<T, EvalError> {
+        let nesting = self.usage.nesting.get() + 1;
+        check(Limit::Depth, self.options.max_depth, nesting)?;
+        self.usage.nesting.set(nesting);
+        let result = f();
+        self.usage.nesting.set(nesting - 1);
+        result
+    }
+}
+
+fn check(limit: Limit, max: Option<usize>, value: usize) -> Result<(), EvalError> {
+    match max {
+        Some(max) if value > max => Err(EvalError::LimitExceeded { limit, max }),
+        _ => Ok(()),
+    }
+}
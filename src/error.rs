@@ -0,0 +1,132 @@
+use std::fmt;
+use ion_rs::{IonError, IonType};
+use peg::str::LineCol;
+use crate::{CompareOp, Literal, Location};
+
+
+/// An error that stopped the evaluation of a path.
+#[derive(Debug, Clone, PartialEq)]
+pub enum EvalError {
+    /// a comparison predicate was checked against a struct, list or sexp, which can't be
+    /// compared to a literal. only raised with [`Strictness::Strict`](crate::Strictness::Strict).
+    NonScalarComparison {
+        /// the type of the value that was compared.
+        found: IonType,
+        op: CompareOp,
+        value: Literal,
+        /// where the compared value was found in the document.
+        location: Location,
+    },
+    /// evaluation would have exceeded one of the limits set in its
+    /// [`EvalOptions`](crate::EvalOptions).
+    LimitExceeded {
+        limit: Limit,
+        /// the configured maximum.
+        max: usize,
+    },
+}
+
+/// One of the evaluation limits of [`EvalOptions`](crate::EvalOptions).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Limit {
+    Depth,
+    Visited,
+    Results,
+    ContextSize,
+    Values,
+}
+
+impl fmt::Display for Limit {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Limit::Depth => "evaluation depth",
+            Limit::Visited => "number of visited elements",
+            Limit::Results => "number of results",
+            Limit::ContextSize => "context size",
+            Limit::Values => "number of computed values",
+        })
+    }
+}
+
+impl fmt::Display for EvalError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            EvalError::NonScalarComparison { found, op, value, location } => write!(f,
+                "can't compare {} at {} to a literal ({:?} {:?})", found, location, op, value),
+            EvalError::LimitExceeded { limit, max } => write!(f, "{} exceeded the limit of {}", limit, max),
+        }
+    }
+}
+
+impl std::error::Error for EvalError {}
+
+
+/// An error that stopped the evaluation of a path against an Ion stream that's read while
+/// evaluating, e.g. by [`CompiledPath::try_match_reader`](crate::CompiledPath::try_match_reader).
+#[derive(Debug)]
+pub enum StreamError {
+    /// the stream couldn't be read.
+    Read(IonError),
+    Eval(EvalError),
+}
+
+impl From<IonError> for StreamError {
+    fn from(error: IonError) -> Self {
+        StreamError::Read(error)
+    }
+}
+
+impl From<EvalError> for StreamError {
+    fn from(error: EvalError) -> Self {
+        StreamError::Eval(error)
+    }
+}
+
+impl fmt::Display for StreamError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            StreamError::Read(e) => write!(f, "can't read the stream: {}", e),
+            StreamError::Eval(e) => e.fmt(f),
+        }
+    }
+}
+
+impl std::error::Error for StreamError {}
+
+
+/// An error returned by [`parser::parse`](crate::parser::parse).
+#[derive(Debug, Clone, PartialEq)]
+pub enum ParseError {
+    /// the query is longer than [`ParseOptions::max_length`](crate::ParseOptions::max_length).
+    TooLong { length: usize, max: usize },
+    /// predicates are nested deeper than
+    /// [`ParseOptions::max_predicate_depth`](crate::ParseOptions::max_predicate_depth).
+    /// `offset` is the byte offset of the first `[` past the limit.
+    TooDeep { offset: usize, max: usize },
+    /// the query has more than [`ParseOptions::max_segments`](crate::ParseOptions::max_segments)
+    /// segments. `offset` is the byte offset of the first segment past the limit.
+    TooManySegments { offset: usize, max: usize },
+    /// a literal is longer than
+    /// [`ParseOptions::max_literal_size`](crate::ParseOptions::max_literal_size).
+    LiteralTooLarge { offset: usize, length: usize, max: usize },
+    /// the query is within the limits, but isn't a valid path.
+    Syntax(peg::error::ParseError<LineCol>),
+}
+
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ParseError::TooLong { length, max } => write!(f,
+                "query is {} bytes long, the limit is {}", length, max),
+            ParseError::TooDeep { offset, max } => write!(f,
+                "predicates nested more than {} deep at offset {}", max, offset),
+            ParseError::TooManySegments { offset, max } => write!(f,
+                "query has more than {} segments, at offset {}", max, offset),
+            ParseError::LiteralTooLarge { offset, length, max } => write!(f,
+                "literal at offset {} is {} bytes long, the limit is {}", offset, length, max),
+            ParseError::Syntax(e) => write!(f, "invalid query: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for ParseError {}
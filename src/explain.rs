@@ -0,0 +1,110 @@
+use std::fmt;
+use ion_rs::element::Element;
+use crate::{Location, Path, Segment, SegmentEvent};
+
+
+/// The most sample elements an [`Explanation`] includes.
+pub const MAX_SAMPLES: usize = 5;
+
+/// Why a path did or didn't match an element, as returned by [`explain`].
+#[derive(Debug, Clone, PartialEq)]
+pub struct Explanation {
+    /// the number of matches.
+    pub matches: usize,
+    /// why nothing matched, if nothing did.
+    pub failure: Option<Failure>,
+}
+
+/// The segment at which evaluation ran out of elements.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Failure {
+    /// zero-based index of the first segment that matched nothing.
+    pub index: usize,
+    pub segment: Segment,
+    /// the number of elements the segment was evaluated against.
+    pub input: usize,
+    /// the number of elements that matched the segment's key.
+    pub candidates: usize,
+    /// the part of the segment that removed the last candidates.
+    pub stage: Stage,
+    /// up to [`MAX_SAMPLES`] elements that got furthest. if no element matched the key, these
+    /// are the elements the segment was evaluated against; otherwise the candidates removed by
+    /// `stage`.
+    pub near_matches: Vec<(Location, Element)>,
+}
+
+/// A part of a segment that filters elements.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Stage {
+    Key,
+    Annotations,
+    Type,
+    /// the predicate (`[...]`) with the given (zero-based) index.
+    Predicates(usize),
+}
+
+/// evaluates `path` against `element`, and if nothing matches, finds out why.
+pub fn explain(path: &Path, element: &Element) -> Explanation {
+    let mut failure = None;
+    let matches = path.match_traced(element, &mut |event: &SegmentEvent| {
+        if failure.is_none() && event.output == 0 && event.input > 0 {
+            failure = Some(Failure::from_event(event));
+        }
+    }).len();
+
+    if let Some(Failure { index, stage: Stage::Key, near_matches, .. }) = failure.as_mut() {
+        // the segment's input, i.e. the output of all segments before it
+        let prefix = Path { absolute: path.absolute, segments: path.segments.range(..*index).cloned().collect() };
+        *near_matches = sample(&prefix.match_located(element));
+    }
+    Explanation { matches, failure }
+}
+
+impl Failure {
+    fn from_event(event: &SegmentEvent) -> Self {
+        // filters run in order, so the last one that removed anything removed the last candidates
+        let (stage, removed) = match event.filtered_by_predicates.iter().rposition(|removed| !removed.is_empty()) {
+            Some(pred) => (Stage::Predicates(pred), &event.filtered_by_predicates[pred]),
+            None if !event.filtered_by_type.is_empty() => (Stage::Type, &event.filtered_by_type),
+            None if !event.filtered_by_annotations.is_empty() => (Stage::Annotations, &event.filtered_by_annotations),
+            None => (Stage::Key, &event.filtered_by_annotations),
+        };
+        Failure {
+            index: event.index,
+            segment: event.segment.clone(),
+            input: event.input,
+            candidates: event.candidates,
+            stage,
+            near_matches: sample(removed),
+        }
+    }
+}
+
+fn sample(elements: &[(Location, &Element)]) -> Vec<(Location, Element)> {
+    elements.iter().take(MAX_SAMPLES)
+        .map(|(location, element)| (location.clone(), (*element).clone()))
+        .collect()
+}
+
+impl fmt::Display for Explanation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let failure = match &self.failure {
+            Some(failure) => failure,
+            None => return write!(f, "matched {} element(s)", self.matches),
+        };
+        write!(f, "no matches: segment {}: ", failure.index)?;
+        match failure.stage {
+            Stage::Key => write!(f, "none of {} element(s) had a child matching the key", failure.input)?,
+            Stage::Annotations => write!(f,
+                "{} element(s) matched the key, the last removed by the annotations", failure.candidates)?,
+            Stage::Type => write!(f,
+                "{} element(s) matched the key, the last removed by the type", failure.candidates)?,
+            Stage::Predicates(pred) => write!(f,
+                "{} element(s) matched the key, the last removed by predicate {}", failure.candidates, pred)?,
+        }
+        for (location, element) in failure.near_matches.iter() {
+            write!(f, "\n  near match at {}: {}", location, element)?;
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,417 @@
+use std::fmt;
+use std::ops::RangeInclusive;
+use bigdecimal::BigDecimal;
+use ion_rs::Decimal;
+use num::{BigInt, Integer, Signed, ToPrimitive, Zero};
+use crate::{CompiledPath, EvalContext, EvalError, Literal, Path};
+use crate::location::Node;
+
+
+/// A value computed inside a predicate, e.g. `last() - 3` in `[position() > last() - 3]`.
+///
+/// An expression has any number of values: a path has one for every element it matches, a
+/// literal or function call has one. Arithmetic combines every value on one side with every
+/// value on the other.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Expr {
+    Literal(Literal),
+    /// the elements the path matches, starting at the element being filtered.
+    Path(Box<Path>),
+    Call(Function, Vec<Expr>),
+    Arithmetic(Box<Expr>, ArithOp, Box<Expr>),
+}
+
+/// A function that can be called in a predicate.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Function {
+    /// `position()`, the zero-based position of the element being filtered in its parent
+    /// sequence (or struct). 0 for an element without a parent.
+    Position,
+    /// `last()`, the position of the last item in the parent of the element being filtered.
+    /// 0 for an element without a parent.
+    Last,
+    /// `length(value)`, the number of characters in a string or symbol, bytes in a blob or
+    /// clob, or items in a list or sexp.
+    Length,
+    /// `count(path)`, the number of values of its argument.
+    Count,
+    /// `exists(path)`, true if its argument has any values.
+    Exists,
+    /// `starts_with(text, prefix)`, for strings and symbols.
+    StartsWith,
+    /// `ends_with(text, suffix)`, for strings and symbols.
+    EndsWith,
+    /// `contains(text, part)`, for strings and symbols.
+    Contains,
+    /// `lower(text)`, a string or symbol in lowercase.
+    Lower,
+    /// `upper(text)`, a string or symbol in uppercase.
+    Upper,
+    /// `abs(number)`, the absolute value of a number.
+    Abs,
+    /// `floor(number)`, the largest integer that isn't greater than a number. an integer for
+    /// integers and decimals, a float for floats. no value for decimals with an exponent
+    /// above 1000.
+    Floor,
+    /// `round(number)`, the nearest integer to a number, rounding halves away from zero. an
+    /// integer for integers and decimals, a float for floats. no value for decimals with an
+    /// exponent above 1000, like `floor()`.
+    Round,
+    /// `type(value)`, the name of a value's Ion type as a symbol, e.g. `int` for both `1` and
+    /// `null.int`. compared with a bare type name in `[type() = struct]`.
+    Type,
+    /// `is_null(value)`, true for `null` and typed nulls such as `null.int`.
+    IsNull,
+}
+
+impl Function {
+    /// the function called `name` in a query.
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "position" => Some(Function::Position),
+            "last" => Some(Function::Last),
+            "length" => Some(Function::Length),
+            "count" => Some(Function::Count),
+            "exists" => Some(Function::Exists),
+            "starts_with" => Some(Function::StartsWith),
+            "ends_with" => Some(Function::EndsWith),
+            "contains" => Some(Function::Contains),
+            "lower" => Some(Function::Lower),
+            "upper" => Some(Function::Upper),
+            "abs" => Some(Function::Abs),
+            "floor" => Some(Function::Floor),
+            "round" => Some(Function::Round),
+            "type" => Some(Function::Type),
+            "is_null" => Some(Function::IsNull),
+            _ => None,
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Function::Position => "position",
+            Function::Last => "last",
+            Function::Length => "length",
+            Function::Count => "count",
+            Function::Exists => "exists",
+            Function::StartsWith => "starts_with",
+            Function::EndsWith => "ends_with",
+            Function::Contains => "contains",
+            Function::Lower => "lower",
+            Function::Upper => "upper",
+            Function::Abs => "abs",
+            Function::Floor => "floor",
+            Function::Round => "round",
+            Function::Type => "type",
+            Function::IsNull => "is_null",
+        }
+    }
+
+    /// the numbers of arguments the function takes. functions of a single value use the element
+    /// being filtered if it's left out, e.g. `length()`.
+    pub fn arity(&self) -> RangeInclusive<usize> {
+        match self {
+            Function::Position | Function::Last => 0..=0,
+            Function::Count | Function::Exists => 1..=1,
+            Function::StartsWith | Function::EndsWith | Function::Contains => 2..=2,
+            Function::Length | Function::Lower | Function::Upper | Function::Abs | Function::Floor | Function::Round
+                | Function::Type | Function::IsNull => 0..=1,
+        }
+    }
+
+    /// true for functions that return a boolean, which can be used as a predicate on their own.
+    pub fn is_test(&self) -> bool {
+        matches!(self, Function::Exists | Function::StartsWith | Function::EndsWith | Function::Contains | Function::IsNull)
+    }
+
+    /// the result of calling the function with a value for each of its arguments.
+    fn apply(&self, args: &[&Operand]) -> Option<Literal> {
+        match (self, args) {
+            (Function::Length, [Operand::Value(value)]) => {
+                let length = match value {
+                    Literal::String(s) | Literal::Symbol(s) => s.chars().count(),
+                    Literal::Blob(b) | Literal::Clob(b) => b.len(),
+                    _ => return None,
+                };
+                Some(Literal::Integer(BigInt::from(length)))
+            },
+            (Function::Length, [Operand::Container(node)]) =>
+                node.element.as_sequence().map(|seq| Literal::Integer(BigInt::from(seq.len()))),
+            (Function::StartsWith | Function::EndsWith | Function::Contains, [Operand::Value(text), Operand::Value(part)]) => {
+                let (text, part) = (text_of(text)?, text_of(part)?);
+                Some(Literal::Boolean(match self {
+                    Function::StartsWith => text.starts_with(part),
+                    Function::EndsWith => text.ends_with(part),
+                    _ => text.contains(part),
+                }))
+            },
+            (Function::Lower | Function::Upper, [Operand::Value(value)]) => {
+                let convert = |s: &str| if *self == Function::Lower { s.to_lowercase() } else { s.to_uppercase() };
+                match value {
+                    Literal::String(s) => Some(Literal::String(convert(s))),
+                    Literal::Symbol(s) => Some(Literal::Symbol(convert(s))),
+                    _ => None,
+                }
+            },
+            (Function::Abs, [Operand::Value(value)]) => match value {
+                Literal::Integer(i) => Some(Literal::Integer(i.abs())),
+                Literal::Float(f) => Some(Literal::Float(f.abs())),
+                Literal::Decimal(_) => Some(Literal::Decimal(Decimal::from(to_big_decimal(value)?.abs()))),
+                _ => None,
+            },
+            (Function::Floor | Function::Round, [Operand::Value(value)]) => match value {
+                Literal::Integer(_) => Some(value.clone()),
+                Literal::Float(f) => Some(Literal::Float(if *self == Function::Floor { f.floor() } else { f.round() })),
+                Literal::Decimal(_) => {
+                    let d = to_big_decimal(value)?;
+                    Some(Literal::Integer(if *self == Function::Floor { floor(&d)? } else { round(&d)? }))
+                },
+                _ => None,
+            },
+            (Function::Type, [Operand::Value(value)]) => Some(Literal::Symbol(value.ion_type().to_string())),
+            (Function::Type, [Operand::Container(node)]) => Some(Literal::Symbol(node.element.ion_type().to_string())),
+            (Function::IsNull, [operand]) => Some(Literal::Boolean(matches!(operand, Operand::Value(Literal::Null(_))))),
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Display for Function {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}()", self.name())
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ArithOp {
+    Add, Subtract
+}
+
+impl ArithOp {
+    /// `lhs <op> rhs`, if both are numbers. integers stay integers, anything combined with a
+    /// float is a float, and anything else combined with a decimal is a decimal.
+    pub fn apply(&self, lhs: &Literal, rhs: &Literal) -> Option<Literal> {
+        match (lhs, rhs) {
+            (Literal::Integer(a), Literal::Integer(b)) => Some(Literal::Integer(match self {
+                ArithOp::Add => a + b,
+                ArithOp::Subtract => a - b,
+            })),
+            (Literal::Float(_), _) | (_, Literal::Float(_)) => {
+                let (a, b) = (to_f64(lhs)?, to_f64(rhs)?);
+                Some(Literal::Float(match self {
+                    ArithOp::Add => a + b,
+                    ArithOp::Subtract => a - b,
+                }))
+            },
+            _ => {
+                let (a, b) = (to_big_decimal(lhs)?, to_big_decimal(rhs)?);
+                Some(Literal::Decimal(Decimal::from(match self {
+                    ArithOp::Add => a + b,
+                    ArithOp::Subtract => a - b,
+                })))
+            },
+        }
+    }
+}
+
+fn to_f64(lit: &Literal) -> Option<f64> {
+    match lit {
+        Literal::Integer(i) => i.to_f64(),
+        Literal::Float(f) => Some(*f),
+        Literal::Decimal(_) => to_big_decimal(lit)?.to_f64(),
+        _ => None,
+    }
+}
+
+fn text_of(lit: &Literal) -> Option<&str> {
+    match lit {
+        Literal::String(s) | Literal::Symbol(s) => Some(s),
+        _ => None,
+    }
+}
+
+/// the largest exponent of a decimal that `floor()` and `round()` turn into an integer. an
+/// exponent like the one in `1d2000000000` would make an integer with billions of digits.
+const MAX_INTEGRAL_EXPONENT: u64 = 1000;
+
+fn floor(d: &BigDecimal) -> Option<BigInt> {
+    let (digits, scale) = d.as_bigint_and_exponent();
+    if scale <= 0 {
+        // already an integer
+        return integral(digits, scale);
+    }
+    if scale.unsigned_abs() > digits.bits() {
+        // less than 1 in magnitude, as 10^scale > 2^bits > |digits|
+        return Some(if digits.is_negative() { BigInt::from(-1) } else { BigInt::zero() });
+    }
+    Some(digits.div_floor(&BigInt::from(10).pow(scale as u32)))
+}
+
+fn round(d: &BigDecimal) -> Option<BigInt> {
+    let (digits, scale) = d.as_bigint_and_exponent();
+    if scale <= 0 {
+        return integral(digits, scale);
+    }
+    if scale.unsigned_abs() > digits.bits() {
+        // less than 0.2 in magnitude
+        return Some(BigInt::zero());
+    }
+    let unit = BigInt::from(10).pow(scale as u32);
+    let (mut whole, rest) = digits.abs().div_rem(&unit);
+    if rest * 2 >= unit {
+        whole += 1;
+    }
+    Some(if digits.is_negative() { -whole } else { whole })
+}
+
+/// `digits * 10^-scale` for a `scale` that isn't positive, unless it's too large.
+fn integral(digits: BigInt, scale: i64) -> Option<BigInt> {
+    if digits.is_zero() {
+        return Some(digits);
+    }
+    match scale.unsigned_abs() {
+        exponent if exponent > MAX_INTEGRAL_EXPONENT => None,
+        exponent => Some(digits * BigInt::from(10).pow(exponent as u32)),
+    }
+}
+
+fn to_big_decimal(lit: &Literal) -> Option<BigDecimal> {
+    match lit {
+        Literal::Integer(i) => Some(BigDecimal::from(i.clone())),
+        // should only fail on negative zero, which is zero all the same
+        Literal::Decimal(d) => Some(BigDecimal::try_from(d.clone()).unwrap_or(BigDecimal::from(0))),
+        _ => None,
+    }
+}
+
+
+/// A single value of an expression.
+#[derive(Debug, Clone)]
+pub(crate) enum Operand<'a> {
+    Value(Literal),
+    /// a struct, list or sexp matched by a path, which has no literal form.
+    Container(Node<'a>),
+}
+
+impl<'a> Operand<'a> {
+    /// the value of the element `node`.
+    fn of(node: &Node<'a>) -> Self {
+        match Literal::try_from(node.element.value().clone()) {
+            Ok(lit) => Operand::Value(lit),
+            Err(()) => Operand::Container(node.clone()),
+        }
+    }
+}
+
+/// An [`Expr`], prepared for evaluation.
+#[derive(Debug, Clone)]
+pub(crate) enum CompiledExpr {
+    Literal(Literal),
+    Path(CompiledPath),
+    Call(Function, Vec<CompiledExpr>),
+    Arithmetic(Box<CompiledExpr>, ArithOp, Box<CompiledExpr>),
+}
+
+impl CompiledExpr {
+    pub fn new(expr: &Expr) -> Self {
+        match expr {
+            Expr::Literal(lit) => CompiledExpr::Literal(lit.clone()),
+            Expr::Path(path) => CompiledExpr::Path(path.compile()),
+            Expr::Call(function, args) => CompiledExpr::Call(*function, args.iter().map(CompiledExpr::new).collect()),
+            Expr::Arithmetic(lhs, op, rhs) => CompiledExpr::Arithmetic(
+                Box::new(CompiledExpr::new(lhs)), *op, Box::new(CompiledExpr::new(rhs))),
+        }
+    }
+
+    /// true if evaluating this expression can depend on anything outside of the element being
+    /// filtered: its position, or elements reached by absolute paths or by leaving it.
+    pub fn looks_outside(&self) -> bool {
+        match self {
+            CompiledExpr::Literal(_) => false,
+            CompiledExpr::Path(path) => path.absolute || path.needs_whole_values(),
+            CompiledExpr::Call(function, args) =>
+                matches!(function, Function::Position | Function::Last) || args.iter().any(CompiledExpr::looks_outside),
+            CompiledExpr::Arithmetic(lhs, _, rhs) => lhs.looks_outside() || rhs.looks_outside(),
+        }
+    }
+
+    /// the values of this expression for the element `node`.
+    pub fn evaluate<'a>(&self, ctx: &EvalContext<'a>, node: &Node<'a>) -> Result<Vec<Operand<'a>>, EvalError> {
+        match self {
+            CompiledExpr::Literal(lit) => Ok(vec![Operand::Value(lit.clone())]),
+            CompiledExpr::Path(path) => {
+                let mut matches = path.iter_node(ctx, node);
+                let mut values = Vec::new();
+                while let Some(found) = matches.try_next_node()? {
+                    values.push(Operand::of(&found));
+                }
+                Ok(values)
+            },
+            CompiledExpr::Call(Function::Position, _) => Ok(vec![Operand::Value(Literal::Integer(BigInt::from(position(node))))]),
+            CompiledExpr::Call(Function::Last, _) => Ok(vec![Operand::Value(Literal::Integer(BigInt::from(last(node))))]),
+            CompiledExpr::Call(Function::Count, args) => match args.as_slice() {
+                [arg] => {
+                    let count = arg.evaluate(ctx, node)?.len();
+                    Ok(vec![Operand::Value(Literal::Integer(BigInt::from(count)))])
+                },
+                // built without its argument, which the parser doesn't allow
+                _ => Ok(Vec::new()),
+            },
+            CompiledExpr::Call(Function::Exists, args) => match args.as_slice() {
+                [arg] => {
+                    let exists = !arg.evaluate(ctx, node)?.is_empty();
+                    Ok(vec![Operand::Value(Literal::Boolean(exists))])
+                },
+                _ => Ok(Vec::new()),
+            },
+            CompiledExpr::Call(function, args) => {
+                let args = match args.is_empty() {
+                    true => vec![vec![Operand::of(node)]],
+                    false => args.iter().map(|arg| arg.evaluate(ctx, node)).collect::<Result<Vec<_>, _>>()?,
+                };
+                // every combination of the arguments' values
+                ctx.add_values(args.iter().fold(1, |n, values| n.saturating_mul(values.len())))?;
+                let mut combinations: Vec<Vec<&Operand>> = vec![Vec::new()];
+                for values in args.iter() {
+                    combinations = combinations.into_iter()
+                        .flat_map(|combination| values.iter().map(move |value| [combination.as_slice(), &[value]].concat()))
+                        .collect();
+                }
+                Ok(combinations.iter().filter_map(|combination| function.apply(combination)).map(Operand::Value).collect())
+            },
+            CompiledExpr::Arithmetic(lhs, op, rhs) => {
+                let (lhs, rhs) = (lhs.evaluate(ctx, node)?, rhs.evaluate(ctx, node)?);
+                ctx.add_values(lhs.len().saturating_mul(rhs.len()))?;
+                let mut values = Vec::new();
+                for a in lhs.iter() {
+                    for b in rhs.iter() {
+                        if let (Operand::Value(a), Operand::Value(b)) = (a, b) {
+                            values.extend(op.apply(a, b).map(Operand::Value));
+                        }
+                    }
+                }
+                Ok(values)
+            },
+        }
+    }
+}
+
+fn position(node: &Node) -> usize {
+    match node.parent {
+        Some(_) => node.position,
+        None => 0,
+    }
+}
+
+fn last(node: &Node) -> usize {
+    let parent = match node.parent.as_ref() {
+        Some(parent) => &parent.element,
+        None => return 0,
+    };
+    let len = match (parent.as_sequence(), parent.as_struct()) {
+        (Some(sequence), _) => sequence.len(),
+        (_, Some(st)) => st.len(),
+        _ => 0,
+    };
+    len.saturating_sub(1)
+}
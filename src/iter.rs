@@ -0,0 +1,182 @@
+use std::borrow::Cow;
+use ion_rs::element::Element;
+use crate::{CompiledPath, EvalContext, EvalError, Path};
+use crate::compiled::{reaches_once, run_segments, KeyMatches};
+use crate::location::Node;
+
+
+/// Lazily evaluates a [`Path`], yielding matches depth-first as they are found.
+///
+/// Only the part of the document needed to produce the next match is visited, so stopping
+/// early (e.g. with [`Iterator::take`] or [`Path::first`]) skips the rest of the document.
+/// Matches are yielded in the same order [`Path::match_ref`] returns them.
+///
+/// Segments that can reach the same element from several places (like `..`, or the second
+/// `//` in `//a//b`) only reach each element once, which needs all of their context. A path
+/// with such segments is evaluated up to the last of them on the first call, and lazily from
+/// there on.
+///
+/// Evaluation follows the options of the context the iterator was created with. Use
+/// [`PathIter::try_next`] to see errors; as an [`Iterator`], the iterator ends at the first
+/// error, which is then available from [`PathIter::error`].
+pub struct PathIter<'p, 'a> {
+    path: Cow<'p, CompiledPath>,
+    ctx: EvalContext<'a>,
+    /// the number of segments evaluated up front, up to and including the last one that
+    /// has to reach each element only once.
+    skip: usize,
+    /// the starting nodes, until those segments have been evaluated.
+    pending: Option<Vec<Node<'a>>>,
+    /// `frames[i]` holds the remaining candidates for `path.segments[skip + i]`.
+    frames: Vec<KeyMatches<'a>>,
+    /// only used by paths without any further segments, which match their starting nodes.
+    starts: Option<std::vec::IntoIter<Node<'a>>>,
+    error: Option<EvalError>,
+    /// false for the iterators used by predicates, whose matches aren't results.
+    count_results: bool,
+}
+
+impl<'p, 'a> PathIter<'p, 'a> {
+    fn new(path: Cow<'p, CompiledPath>, ctx: EvalContext<'a>, starts: Vec<Node<'a>>) -> Self {
+        let skip = (0..path.segments.len()).rposition(|k| reaches_once(&path.segments, k)).map_or(0, |k| k + 1);
+        let mut iter = PathIter { path, ctx, skip, pending: None, frames: Vec::new(), starts: None, error: None, count_results: true };
+        if skip > 0 {
+            iter.pending = Some(starts);
+        }
+        else {
+            iter.start(starts);
+        }
+        iter
+    }
+
+    /// continues with the segments after the first `skip` from `context`.
+    fn start(&mut self, context: Vec<Node<'a>>) {
+        match self.path.segments.get(self.skip) {
+            Some(seg) => self.frames.push(seg.key_matches(&self.ctx, context)),
+            None => self.starts = Some(context.into_iter()),
+        }
+    }
+
+    /// the next match, or the error that stopped evaluation.
+    pub fn try_next(&mut self) -> Result<Option<&'a Element>, EvalError> {
+        let next = self.try_next_node()?;
+        if next.is_some() && self.count_results {
+            self.ctx.add_results(1)?;
+        }
+        Ok(next.map(|node| node.element))
+    }
+
+    /// the error that ended iteration, if any.
+    pub fn error(&self) -> Option<&EvalError> {
+        self.error.as_ref()
+    }
+
+    pub(crate) fn try_next_node(&mut self) -> Result<Option<Node<'a>>, EvalError> {
+        if let Some(starts) = self.pending.take() {
+            let context = run_segments(&self.ctx, &self.path.segments[..self.skip], starts, None)?;
+            self.start(context);
+        }
+        if let Some(starts) = self.starts.as_mut() {
+            return Ok(starts.next());
+        }
+        while !self.frames.is_empty() {
+            let depth = self.frames.len() - 1;
+            let seg = &self.path.segments[self.skip + depth];
+            match self.frames[depth].next(&seg.key, &self.ctx)? {
+                None => { self.frames.pop(); },
+                Some(node) => {
+                    let matching = seg.name_matching(self.ctx.options());
+                    if !seg.match_annotations(node.element.annotations(), matching) || !seg.match_type(node.element.ion_type())
+                        || !seg.match_predicates(&self.ctx, &node)? {
+                        continue;
+                    }
+                    match self.path.segments.get(self.skip + depth + 1) {
+                        Some(next_seg) => self.frames.push(next_seg.key_matches(&self.ctx, vec![node])),
+                        None => return Ok(Some(node)),
+                    }
+                }
+            }
+        }
+        Ok(None)
+    }
+}
+
+impl<'p, 'a> Iterator for PathIter<'p, 'a> {
+    type Item = &'a Element;
+
+    fn next(&mut self) -> Option<&'a Element> {
+        match self.try_next() {
+            Ok(next) => next,
+            Err(error) => {
+                self.error = Some(error);
+                self.frames.clear();
+                None
+            }
+        }
+    }
+}
+
+impl CompiledPath {
+    /// lazily evaluates this path against `root_element`. see [`PathIter`].
+    pub fn iter<'p, 'a>(&'p self, root_element: &'a Element) -> PathIter<'p, 'a> {
+        let ctx = EvalContext::new(std::slice::from_ref(root_element));
+        PathIter::new(Cow::Borrowed(self), ctx, vec![Node::root(root_element, 0)])
+    }
+
+    /// lazy version of [`CompiledPath::match_element_in`].
+    pub fn iter_in<'p, 'a>(&'p self, ctx: &EvalContext<'a>, element: &'a Element) -> PathIter<'p, 'a> {
+        PathIter::new(Cow::Borrowed(self), ctx.clone(), self.start_nodes(ctx, &Node::root(element, 0)))
+    }
+
+    /// iterates over the matches of a predicate subquery evaluated at `node`.
+    pub(crate) fn iter_node<'p, 'a>(&'p self, ctx: &EvalContext<'a>, node: &Node<'a>) -> PathIter<'p, 'a> {
+        let mut iter = PathIter::new(Cow::Borrowed(self), ctx.clone(), self.start_nodes(ctx, node));
+        iter.count_results = false;
+        iter
+    }
+
+    /// the first match in `root_element`, without looking for any further matches.
+    pub fn first<'a>(&self, root_element: &'a Element) -> Option<&'a Element> {
+        self.iter(root_element).next()
+    }
+
+    /// true if anything in `root_element` matches, stopping at the first match.
+    pub fn any(&self, root_element: &Element) -> bool {
+        self.first(root_element).is_some()
+    }
+
+    /// the number of matches in `root_element`, without collecting them.
+    pub fn count(&self, root_element: &Element) -> usize {
+        self.iter(root_element).count()
+    }
+}
+
+impl Path {
+    /// lazily evaluates this path against `root_element`. see [`PathIter`].
+    pub fn iter<'p, 'a>(&'p self, root_element: &'a Element) -> PathIter<'p, 'a> {
+        let ctx = EvalContext::new(std::slice::from_ref(root_element));
+        PathIter::new(Cow::Owned(self.compile()), ctx, vec![Node::root(root_element, 0)])
+    }
+
+    /// lazy version of [`Path::match_element_in`].
+    pub fn iter_in<'p, 'a>(&'p self, ctx: &EvalContext<'a>, element: &'a Element) -> PathIter<'p, 'a> {
+        let path = self.compile();
+        let starts = path.start_nodes(ctx, &Node::root(element, 0));
+        PathIter::new(Cow::Owned(path), ctx.clone(), starts)
+    }
+
+    /// the first match in `root_element`, without looking for any further matches.
+    pub fn first<'a>(&self, root_element: &'a Element) -> Option<&'a Element> {
+        self.iter(root_element).next()
+    }
+
+    /// true if anything in `root_element` matches, stopping at the first match.
+    pub fn any(&self, root_element: &Element) -> bool {
+        self.first(root_element).is_some()
+    }
+
+    /// the number of matches in `root_element`, without collecting them.
+    pub fn count(&self, root_element: &Element) -> usize {
+        self.iter(root_element).count()
+    }
+}
@@ -13,7 +13,47 @@ mod tests;
 pub mod parser;
 
 pub mod segment;
-pub use segment::Segment;
+pub use segment::{AnnotationKey, Axis, Segment};
+
+pub mod context;
+pub use context::EvalContext;
+
+pub mod options;
+pub use options::{EvalOptions, NameMatching, ParseOptions, Strictness};
+
+pub mod error;
+pub use error::{EvalError, Limit, ParseError, StreamError};
+
+pub mod stream;
+pub use stream::StreamMatch;
+
+pub mod iter;
+pub use iter::PathIter;
+
+pub mod location;
+pub use location::{Location, Step};
+use location::Node;
+
+pub mod expr;
+pub use expr::{ArithOp, Expr, Function};
+
+pub mod compiled;
+pub use compiled::CompiledPath;
+use compiled::CompiledPredicate;
+
+pub mod trace;
+pub use trace::{SegmentEvent, Tracer};
+
+pub mod explain;
+pub use explain::{explain, Explanation, Failure, Stage};
+
+pub mod union;
+pub use union::PathUnion;
+
+pub mod queryset;
+pub use queryset::{QueryMatch, QuerySet};
+
+mod reader;
 
 
 #[derive(Debug, Clone, PartialEq)]
@@ -23,42 +63,80 @@ pub struct Path {
 }
 
 impl Path {
+    #[allow(clippy::should_implement_trait)]
     pub fn next(&mut self) -> Option<Segment> {
         self.segments.pop_front()
     }
 
+    /// prepares this path for repeated evaluation. see [`CompiledPath`].
+    pub fn compile(&self) -> CompiledPath {
+        CompiledPath::new(self)
+    }
+
     pub fn match_element(&self, root_element: Element) -> Vec<Element> {
-        let mut path = self.clone();
-        let mut context: Vec<Element> = vec![root_element];
-        while let Some(seg) = path.next() {
-            println!("[\n{}\n]", context.iter().map(|e| format!("    {}", e.to_string())).collect::<Vec<_>>().join("\n"));
-            let mut next_context = Vec::new();
-            for e in context.iter() {
-                let mut result_set = seg.match_key(e);
-                result_set.retain(|elem| seg.match_annotations(elem));
-                for or_list in seg.predicate_lists.iter() {
-                    result_set.retain(|e| {
-                        for pred in or_list.iter() {
-                            if pred.filter(e) { return true; }
-                        }
-                        false
-                    });
-                }
-                next_context.append(&mut result_set);
-            }
-            context = next_context;
-        }
-        context
+        self.compile().match_element(root_element)
+    }
+
+    /// like [`Path::match_element`], but borrows the root element and returns references to
+    /// the matched elements instead of cloning them.
+    pub fn match_ref<'a>(&self, root_element: &'a Element) -> Vec<&'a Element> {
+        self.compile().match_ref(root_element)
+    }
+
+    /// like [`Path::match_ref`], but also returns where in `root_element` each match was found.
+    pub fn match_located<'a>(&self, root_element: &'a Element) -> Vec<(Location, &'a Element)> {
+        self.compile().match_located(root_element)
+    }
+
+    /// evaluates this path starting at `element`, or at the context's root(s) if the path
+    /// is absolute. the context's options are ignored, see [`Path::try_match_element_in`].
+    pub fn match_element_in<'a>(&self, ctx: &EvalContext<'a>, element: &'a Element) -> Vec<&'a Element> {
+        self.compile().match_element_in(ctx, element)
+    }
+
+    /// like [`Path::match_ref`], reporting what happens at every segment to `tracer`.
+    pub fn match_traced<'a>(&self, root_element: &'a Element, tracer: &mut dyn Tracer) -> Vec<&'a Element> {
+        self.compile().match_traced(root_element, tracer)
+    }
+
+    /// like [`Path::match_element`], evaluating with the given options.
+    pub fn try_match_element(&self, root_element: Element, options: EvalOptions) -> Result<Vec<Element>, EvalError> {
+        self.compile().try_match_element(root_element, options)
+    }
+
+    /// like [`Path::match_element_in`], evaluating with the context's options.
+    pub fn try_match_element_in<'a>(&self, ctx: &EvalContext<'a>, element: &'a Element) -> Result<Vec<&'a Element>, EvalError> {
+        self.compile().try_match_element_in(ctx, element)
     }
 }
 
 
 #[derive(Debug, Clone, PartialEq)]
 pub enum Key {
+    /// `"name"`, always exactly this field name, even if it contains `*` or `?`.
     String(String),
+    /// `name` or `'name'`, a glob pattern if it contains `*` or `?`, otherwise an exact
+    /// field name.
     Symbol(String),
     Index(BigInt),
     Slice(Option<i32>,Option<i32>,Option<i32>),
+    /// `(name|title)`, matches whatever any of the keys matches.
+    Choice(Vec<Key>),
+    /// `~"attr_[0-9]+"`, field names the regular expression matches as a whole.
+    Regex(String),
+}
+
+impl Key {
+    /// true if this key matches field names by a pattern (a glob or a regular expression)
+    /// rather than by exact name. indices and slices are never patterns.
+    pub fn is_pattern(&self) -> bool {
+        match self {
+            Key::Symbol(s) => s.contains(['*', '?']),
+            Key::Regex(_) => true,
+            Key::Choice(keys) => keys.iter().any(Key::is_pattern),
+            Key::String(_) | Key::Index(_) | Key::Slice(..) => false,
+        }
+    }
 }
 
 
@@ -70,57 +148,59 @@ pub enum Predicate {
         op: CompareOp,
         value: Literal
     },
+    /// `lhs <op> rhs`, e.g. `position() > last() - 3`. matches if the comparison holds for any
+    /// value of `lhs` and any value of `rhs`.
+    Condition {
+        lhs: Expr,
+        op: CompareOp,
+        rhs: Expr,
+    },
+    /// a function returning a boolean, e.g. `exists(a/b)` or `starts_with(name, "x")`.
+    /// matches if it returns `true` for any values of its arguments.
+    Call(Function, Vec<Expr>),
 }
 
 impl Predicate {
+    /// filters `element`, treating it as the document root for any absolute subpaths.
     pub fn filter(&self, element: &Element) -> bool {
-        match self {
-            Predicate::Path(path) => {
-                !path.match_element(element.clone()).is_empty()
-            }
-            Predicate::Compare { path, op , value } => {
-                let subquery_res = match path {
-                    Some(p) => p.match_element(element.clone()),
-                    None => vec![element.clone()]
-                };
-                for sub in subquery_res {
-                    let sub_lit: Literal = sub.try_into().unwrap();
-                    match op {
-                        CompareOp::Equal => {
-                            if &sub_lit == value {
-                                return true;
-                            }
-                        },
-                        CompareOp::NotEqual => {
-                            if &sub_lit != value {
-                                return true;
-                            }
-                        },
-                        CompareOp::LessThan => {
-                            if &sub_lit < value {
-                                return true;
-                            }
-                        },
-                        CompareOp::GreaterThan => {
-                            if &sub_lit > value {
-                                return true;
-                            }
-                        },
-                        CompareOp::LessOrEqual => {
-                            if &sub_lit <= value {
-                                return true;
-                            }
-                        },
-                        CompareOp::GreaterOrEqual => {
-                            if &sub_lit >= value {
-                                return true;
-                            }
-                        },
-                    }
-                }
-                false
-            }
-        }
+        self.filter_in(&EvalContext::new(std::slice::from_ref(element)), element)
+    }
+
+    /// the context's options are ignored, see [`Predicate::try_filter_in`].
+    pub fn filter_in<'a>(&self, ctx: &EvalContext<'a>, element: &'a Element) -> bool {
+        // evaluating with the default options never fails
+        self.try_filter_in(&ctx.lenient(), element).unwrap_or(false)
+    }
+
+    /// like [`Predicate::filter`], evaluating with the given options.
+    pub fn try_filter(&self, element: &Element, options: EvalOptions) -> Result<bool, EvalError> {
+        self.try_filter_in(&EvalContext::new(std::slice::from_ref(element)).with_options(options), element)
+    }
+
+    /// like [`Predicate::filter_in`], evaluating with the context's options.
+    pub fn try_filter_in<'a>(&self, ctx: &EvalContext<'a>, element: &'a Element) -> Result<bool, EvalError> {
+        CompiledPredicate::new(self).filter_node(ctx, &Node::root(element, 0))
+    }
+}
+
+
+/// The contents of a segment's `[...]`: predicates combined with `and`, `or` and `not(...)`.
+///
+/// `not` binds most tightly, then `and`, then `or`, and parentheses group, e.g.
+/// `[(a = 1 or b = 2) and not(c = 3)]`. A segment's chained brackets all have to match.
+#[derive(Debug, Clone, PartialEq)]
+pub enum PredicateExpr {
+    Predicate(Predicate),
+    /// matches if all of them match, `true` if empty.
+    And(Vec<PredicateExpr>),
+    /// matches if any of them match, `false` if empty.
+    Or(Vec<PredicateExpr>),
+    Not(Box<PredicateExpr>),
+}
+
+impl From<Predicate> for PredicateExpr {
+    fn from(pred: Predicate) -> Self {
+        PredicateExpr::Predicate(pred)
     }
 }
 
@@ -130,6 +210,31 @@ pub enum CompareOp {
     Equal, NotEqual, LessThan, GreaterThan, LessOrEqual, GreaterOrEqual
 }
 
+impl CompareOp {
+    /// the operator that compares the other way around, e.g. `>` for `<`.
+    pub fn flipped(&self) -> Self {
+        match self {
+            CompareOp::LessThan => CompareOp::GreaterThan,
+            CompareOp::GreaterThan => CompareOp::LessThan,
+            CompareOp::LessOrEqual => CompareOp::GreaterOrEqual,
+            CompareOp::GreaterOrEqual => CompareOp::LessOrEqual,
+            CompareOp::Equal | CompareOp::NotEqual => self.clone(),
+        }
+    }
+
+    /// true if `lhs <op> rhs` holds.
+    pub fn test(&self, lhs: &Literal, rhs: &Literal) -> bool {
+        match self {
+            CompareOp::Equal => lhs == rhs,
+            CompareOp::NotEqual => lhs != rhs,
+            CompareOp::LessThan => lhs < rhs,
+            CompareOp::GreaterThan => lhs > rhs,
+            CompareOp::LessOrEqual => lhs <= rhs,
+            CompareOp::GreaterOrEqual => lhs >= rhs,
+        }
+    }
+}
+
 
 #[derive(Debug, Clone, PartialEq)]
 pub enum Literal {
@@ -145,9 +250,27 @@ pub enum Literal {
     Timestamp(Timestamp),
 }
 
-impl Into<Value> for Literal {
-    fn into(self) -> Value {
+impl Literal {
+    /// the Ion type of the literal, e.g. `Int` for `null.int`.
+    pub fn ion_type(&self) -> IonType {
         match self {
+            Literal::Boolean(_) => IonType::Bool,
+            Literal::Integer(_) => IonType::Int,
+            Literal::Float(_) => IonType::Float,
+            Literal::Decimal(_) => IonType::Decimal,
+            Literal::String(_) => IonType::String,
+            Literal::Symbol(_) => IonType::Symbol,
+            Literal::Null(ty) => *ty,
+            Literal::Blob(_) => IonType::Blob,
+            Literal::Clob(_) => IonType::Clob,
+            Literal::Timestamp(_) => IonType::Timestamp,
+        }
+    }
+}
+
+impl From<Literal> for Value {
+    fn from(lit: Literal) -> Value {
+        match lit {
             Literal::Boolean(b) => Value::Bool(b),
             Literal::Integer(i) => Value::Int(Int::BigInt(i)),
             Literal::Float(f) => Value::Float(f),
@@ -177,7 +300,7 @@ impl TryFrom<Value> for Literal {
         match value {
             Value::Null(ty) => Ok(Literal::Null(ty)),
             Value::Bool(b) => Ok(Literal::Boolean(b)),
-            Value::Int(i) => Ok(Literal::Integer(i.as_big_int().map(|r| r.clone()).unwrap_or(BigInt::from(i.as_i64().unwrap())))),
+            Value::Int(i) => Ok(Literal::Integer(i.as_big_int().cloned().unwrap_or(BigInt::from(i.as_i64().unwrap())))),
             Value::Float(f) => Ok(Literal::Float(f)),
             Value::Decimal(d) => Ok(Literal::Decimal(d)),
             Value::Timestamp(ts) => Ok(Literal::Timestamp(ts)),
@@ -194,7 +317,7 @@ impl PartialEq<Element> for Literal {
     fn eq(&self, other: &Element) -> bool {
         match self {
             Literal::Boolean(b) => other.as_bool().map(|b2| *b == b2) == Some(true),
-            Literal::Integer(i) => other.as_int().map(|int| int.as_big_int().map(|r| r.clone()).unwrap_or(BigInt::from(int.as_i64().unwrap())) == *i) == Some(true),
+            Literal::Integer(i) => other.as_int().map(|int| int.as_big_int().cloned().unwrap_or(BigInt::from(int.as_i64().unwrap())) == *i) == Some(true),
             Literal::Float(f) => other.as_float().map(|f2| *f == f2) == Some(true),
             Literal::Decimal(d) => other.as_decimal().map(|d2| d == d2) == Some(true),
             Literal::String(s) => other.as_string().map(|s2| s.as_str() == s2) == Some(true),
@@ -222,10 +345,7 @@ impl PartialOrd<Literal> for Literal {
                     BigDecimal::from(i.clone())
                 }
                 else if let Literal::Float(f) = self {
-                    match BigDecimal::from_f64(*f) {
-                        Some(dec) => dec,
-                        None => return None
-                    }
+                    BigDecimal::from_f64(*f)?
                 }
                 else if let Literal::Decimal(d) = self {
                     // should only fail on negative zero, convert to positive zero in that case
@@ -236,10 +356,7 @@ impl PartialOrd<Literal> for Literal {
                 let other_value = match other {
                     Literal::Boolean(b) => BigDecimal::from_f64(if *b { 1.0 } else { 0.0 }).unwrap(),
                     Literal::Integer(i) => BigDecimal::from(i.clone()),
-                    Literal::Float(f) => match BigDecimal::from_f64(*f) {
-                        Some(dec) => dec,
-                        None => return None
-                    }
+                    Literal::Float(f) => BigDecimal::from_f64(*f)?,
                     Literal::Decimal(d) => BigDecimal::try_from(d.clone()).unwrap_or(BigDecimal::from(0)),
                     _ => return None
                 };
@@ -277,8 +394,9 @@ impl PartialOrd<Literal> for Literal {
 }
 
 impl PartialOrd<Element> for Literal {
+    /// structs, lists and sexps aren't ordered relative to any literal.
     fn partial_cmp(&self, other: &Element) -> Option<Ordering> {
-        let as_lit: Literal = (other.value().clone()).try_into().unwrap();
+        let as_lit = Literal::try_from(other.value().clone()).ok()?;
         self.partial_cmp(&as_lit)
     }
 }
\ No newline at end of file
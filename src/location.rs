@@ -0,0 +1,139 @@
+use std::collections::HashSet;
+use std::fmt;
+use std::rc::Rc;
+use ion_rs::element::Element;
+
+
+/// A single step of a [`Location`].
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum Step {
+    /// a struct field, by name.
+    Field(String),
+    /// an item of a list or sexp, by zero-based index.
+    Index(usize),
+}
+
+/// Where an element was found, as the steps leading to it from the element the path was
+/// evaluated against.
+///
+/// Displays as a concrete ion-path that selects the element, e.g. `/orders/3/id`. The root
+/// itself displays as `/`.
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Default)]
+pub struct Location {
+    steps: Vec<Step>,
+}
+
+impl Location {
+    pub fn new(steps: Vec<Step>) -> Self {
+        Location { steps }
+    }
+
+    pub fn steps(&self) -> &[Step] {
+        &self.steps
+    }
+
+    pub fn is_root(&self) -> bool {
+        self.steps.is_empty()
+    }
+}
+
+impl fmt::Display for Location {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.steps.is_empty() {
+            return f.write_str("/");
+        }
+        for step in self.steps.iter() {
+            match step {
+                Step::Index(i) => write!(f, "/{}", i)?,
+                Step::Field(name) => {
+                    f.write_str("/")?;
+                    write_field_name(f, name)?;
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+/// writes `name` as an unquoted symbol if the parser would read it back as the same key,
+/// otherwise as a quoted string.
+fn write_field_name(f: &mut fmt::Formatter<'_>, name: &str) -> fmt::Result {
+    let mut chars = name.chars();
+    let is_identifier = match chars.next() {
+        Some(c) => (c.is_ascii_alphabetic() || c == '_' || c == '$')
+            && chars.all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '$'),
+        None => false,
+    };
+    if is_identifier {
+        return f.write_str(name);
+    }
+    f.write_str("\"")?;
+    for c in name.chars() {
+        match c {
+            '"' => f.write_str("\\\"")?,
+            '\\' => f.write_str("\\\\")?,
+            c if c < ' ' => write!(f, "\\x{:02x}", c as u32)?,
+            c if c > '\u{FFFF}' => write!(f, "\\U{:08x}", c as u32)?,
+            c => write!(f, "{}", c)?,
+        }
+    }
+    f.write_str("\"")
+}
+
+
+/// An element reached during evaluation, along with how it was reached.
+#[derive(Debug, Clone)]
+pub(crate) struct Node<'a> {
+    pub element: &'a Element,
+    pub parent: Option<Rc<Node<'a>>>,
+    /// position among the parent's children (or among the roots, for a root node).
+    pub position: usize,
+    /// field name, if the parent is a struct.
+    pub field: Option<&'a str>,
+    /// number of ancestors.
+    pub depth: usize,
+}
+
+impl<'a> Node<'a> {
+    pub fn root(element: &'a Element, position: usize) -> Self {
+        Node { element, parent: None, position, field: None, depth: 0 }
+    }
+
+    pub fn child(parent: &Rc<Node<'a>>, element: &'a Element, position: usize, field: Option<&'a str>) -> Self {
+        Node { element, parent: Some(parent.clone()), position, field, depth: parent.depth + 1 }
+    }
+
+    pub fn location(&self) -> Location {
+        let mut steps = Vec::new();
+        let mut node = self;
+        while let Some(parent) = node.parent.as_ref() {
+            steps.push(match node.field {
+                Some(name) => Step::Field(name.to_string()),
+                None => Step::Index(node.position),
+            });
+            node = parent;
+        }
+        steps.reverse();
+        Location { steps }
+    }
+
+    /// sorts `nodes` into document order and keeps only the first node for each element.
+    pub fn document_order(nodes: &mut Vec<Node<'a>>) {
+        nodes.sort_by_cached_key(Node::document_position);
+        let mut seen = HashSet::new();
+        nodes.retain(|node| seen.insert(node.element as *const Element));
+    }
+
+    /// the positions of this node and its ancestors, starting with the root. sorting nodes
+    /// by these puts them in document order.
+    pub fn document_position(&self) -> Vec<usize> {
+        let mut positions = vec![self.position];
+        let mut node = self;
+        while let Some(parent) = node.parent.as_ref() {
+            positions.push(parent.position);
+            node = parent;
+        }
+        positions.reverse();
+        positions
+    }
+}
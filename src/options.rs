@@ -0,0 +1,179 @@
+/// How comparisons against values that have no literal form (structs, lists and sexps) are
+/// treated.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Strictness {
+    /// the comparison doesn't match.
+    #[default]
+    Lenient,
+    /// evaluation stops with [`EvalError::NonScalarComparison`](crate::EvalError::NonScalarComparison).
+    Strict,
+}
+
+/// How field names and annotations are compared with the names in a query.
+///
+/// Set per segment with a suffix after the key (`/userid~i`, `/name~n`, `/name~in`), or for
+/// a whole evaluation with [`EvalOptions::with_name_matching`]. A segment uses every setting
+/// enabled by either.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct NameMatching {
+    /// `~i`, compare the lowercase forms of the names.
+    pub ignore_case: bool,
+    /// `~n`, compare the Unicode NFC forms of the names, so that composed and decomposed
+    /// characters are the same.
+    pub normalize: bool,
+}
+
+impl NameMatching {
+    /// names have to be exactly the same.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn ignore_case(mut self) -> Self {
+        self.ignore_case = true;
+        self
+    }
+
+    pub fn normalize(mut self) -> Self {
+        self.normalize = true;
+        self
+    }
+
+    pub fn is_exact(&self) -> bool {
+        !self.ignore_case && !self.normalize
+    }
+
+    /// every setting enabled by either `self` or `other`.
+    pub fn union(self, other: NameMatching) -> Self {
+        NameMatching {
+            ignore_case: self.ignore_case || other.ignore_case,
+            normalize: self.normalize || other.normalize,
+        }
+    }
+}
+
+
+/// Settings for a single evaluation, carried by its [`EvalContext`](crate::EvalContext).
+///
+/// Only evaluation methods that can report an [`EvalError`](crate::EvalError) (the `try_*`
+/// methods and [`PathIter`](crate::PathIter)) take options into account; the others always
+/// evaluate with the defaults.
+///
+/// The limits guard against queries that would otherwise do an unbounded amount of work, e.g.
+/// when queries come from untrusted sources. Exceeding one stops evaluation with
+/// [`EvalError::LimitExceeded`](crate::EvalError::LimitExceeded). All limits are off by default.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct EvalOptions {
+    pub strictness: Strictness,
+    /// how deep evaluation may go: neither elements nested more than this many levels below
+    /// the root, nor predicates nested more than this many levels inside each other are
+    /// evaluated.
+    pub max_depth: Option<usize>,
+    /// the number of elements evaluation may reach, including those passed through by recursive
+    /// segments and those reached by predicate subqueries.
+    pub max_visited: Option<usize>,
+    /// the number of matches that may be returned.
+    pub max_results: Option<usize>,
+    /// the number of elements that may be held between two segments of an eagerly evaluated
+    /// path (e.g. by [`Path::match_element`](crate::Path::match_element)). lazy evaluation
+    /// doesn't hold any, so this doesn't apply to [`PathIter`](crate::PathIter).
+    pub max_context_size: Option<usize>,
+    /// the number of values predicates may compute from other values: every combination of a
+    /// function's arguments, every pair of operands of `+` and `-`, and every pair of values a
+    /// comparison of two expressions tests. these grow with the product of the numbers of
+    /// values involved, e.g. in `[a/* + b/* = c/*]`.
+    pub max_values: Option<usize>,
+    /// how every segment compares names, in addition to its own settings.
+    pub name_matching: NameMatching,
+}
+
+impl EvalOptions {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn with_strictness(mut self, strictness: Strictness) -> Self {
+        self.strictness = strictness;
+        self
+    }
+
+    /// shorthand for `with_strictness(Strictness::Strict)`.
+    pub fn strict(self) -> Self {
+        self.with_strictness(Strictness::Strict)
+    }
+
+    pub fn with_max_depth(mut self, max: usize) -> Self {
+        self.max_depth = Some(max);
+        self
+    }
+
+    pub fn with_max_visited(mut self, max: usize) -> Self {
+        self.max_visited = Some(max);
+        self
+    }
+
+    pub fn with_max_results(mut self, max: usize) -> Self {
+        self.max_results = Some(max);
+        self
+    }
+
+    pub fn with_max_context_size(mut self, max: usize) -> Self {
+        self.max_context_size = Some(max);
+        self
+    }
+
+    pub fn with_max_values(mut self, max: usize) -> Self {
+        self.max_values = Some(max);
+        self
+    }
+
+    pub fn with_name_matching(mut self, matching: NameMatching) -> Self {
+        self.name_matching = matching;
+        self
+    }
+}
+
+
+/// Limits on the size and complexity of a query string, checked by
+/// [`parser::parse`](crate::parser::parse) before the query is parsed.
+///
+/// Meant for queries from untrusted sources: deeply nested predicates in particular can exhaust
+/// the stack while parsing. All limits are off by default.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct ParseOptions {
+    /// the length of the query, in bytes.
+    pub max_length: Option<usize>,
+    /// how many predicates may be nested inside each other, e.g. 2 for `/a[b[c]]`. also limits
+    /// how many parentheses may be nested inside of predicates, e.g. 2 for `/a[not((b or c))]`.
+    pub max_predicate_depth: Option<usize>,
+    /// the number of segments in the whole query, including those of predicate subpaths.
+    pub max_segments: Option<usize>,
+    /// the length of a single literal, key or annotation, in bytes (including any quotes).
+    pub max_literal_size: Option<usize>,
+}
+
+impl ParseOptions {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn with_max_length(mut self, max: usize) -> Self {
+        self.max_length = Some(max);
+        self
+    }
+
+    pub fn with_max_predicate_depth(mut self, max: usize) -> Self {
+        self.max_predicate_depth = Some(max);
+        self
+    }
+
+    pub fn with_max_segments(mut self, max: usize) -> Self {
+        self.max_segments = Some(max);
+        self
+    }
+
+    pub fn with_max_literal_size(mut self, max: usize) -> Self {
+        self.max_literal_size = Some(max);
+        self
+    }
+}
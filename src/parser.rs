@@ -2,11 +2,12 @@ use std::collections::VecDeque;
 use num::{BigInt, Num};
 use base64::Engine;
 use ion_rs::IonType;
-use super::{Path, Segment, Key, Literal, Predicate, CompareOp};
+use regex::Regex;
+use super::{AnnotationKey, ArithOp, Axis, Expr, Function, NameMatching, Path, PathUnion, Segment, Key, Literal, Predicate, PredicateExpr, CompareOp, ParseError, ParseOptions};
 
 
 fn unescape(s: &str) -> Result<String, &'static str> {
-    const E: &'static str = "invalid escape sequence";
+    const E: &str = "invalid escape sequence";
 
     let mut string = String::new();
     let mut escaping = false;
@@ -121,6 +122,154 @@ fn unescape(s: &str) -> Result<String, &'static str> {
 }
 
 
+/// parses `query` after checking it against the limits in `options`.
+///
+/// the limits are checked by a quick scan of the query text, so oversized queries are rejected
+/// before any parsing happens.
+pub fn parse(query: &str, options: ParseOptions) -> Result<Path, ParseError> {
+    check_limits(query, &options)?;
+    ionpath_parser::path(query).map_err(ParseError::Syntax)
+}
+
+/// parses a union of one or more paths, `/a/b | /c/d`, checking `options` like [`parse`].
+pub fn parse_union(query: &str, options: ParseOptions) -> Result<PathUnion, ParseError> {
+    check_limits(query, &options)?;
+    ionpath_parser::union(query).map_err(ParseError::Syntax)
+}
+
+fn check_limits(query: &str, options: &ParseOptions) -> Result<(), ParseError> {
+    fn exceeds(max: Option<usize>, value: usize) -> Option<usize> {
+        max.filter(|max| value > *max)
+    }
+
+    if let Some(max) = exceeds(options.max_length, query.len()) {
+        return Err(ParseError::TooLong { length: query.len(), max });
+    }
+    let bytes = query.as_bytes();
+    let mut depth = 0;
+    // parentheses outside of predicates, which group choices rather than paths
+    let mut parens = 0;
+    // parentheses inside of predicates, which can group predicates
+    let mut groups = 0;
+    let mut segments = 0;
+    // true at the start of the query, of a predicate, after `or`, `and` and `not`, and after a
+    // union's `|`, where a path can start
+    let mut path_start = true;
+    let mut i = 0;
+    while i < bytes.len() {
+        let start = i;
+        let c = bytes[i];
+        i += 1;
+        let mut starts_segment = false;
+        match c {
+            b' ' | b'\t' | b'\r' | b'\n' | b'\x0B' | b'\x0C' | b'\\' => continue,
+            b'/' => {
+                // `//` is a single (recursive) segment
+                starts_segment = start == 0 || bytes[start - 1] != b'/';
+            },
+            b'[' => {
+                depth += 1;
+                if let Some(max) = exceeds(options.max_predicate_depth, depth) {
+                    return Err(ParseError::TooDeep { offset: start, max });
+                }
+                path_start = true;
+                continue;
+            },
+            b']' => { depth = usize::saturating_sub(depth, 1); },
+            b'=' | b'!' | b'<' | b'>' => {},
+            b'(' if depth == 0 => {
+                parens += 1;
+                starts_segment = path_start;
+            },
+            b'(' => {
+                groups += 1;
+                if let Some(max) = exceeds(options.max_predicate_depth, groups) {
+                    return Err(ParseError::TooDeep { offset: start, max });
+                }
+                starts_segment = path_start;
+            },
+            b')' => {
+                if depth == 0 { parens = usize::saturating_sub(parens, 1); }
+                else { groups = usize::saturating_sub(groups, 1); }
+                continue;
+            },
+            b'|' => {
+                path_start = depth == 0 && parens == 0;
+                continue;
+            },
+            b':' => continue,
+            _ => {
+                i = match c {
+                    b'\'' if bytes[i..].starts_with(b"''") => skip_quoted(bytes, i + 2, b"'''"),
+                    b'\'' => skip_quoted(bytes, i, b"'"),
+                    b'"' => skip_quoted(bytes, i, b"\""),
+                    b'{' if bytes[i..].starts_with(b"{") => skip_lob(bytes, i + 1),
+                    _ => skip_token(bytes, i),
+                };
+                if let Some(max) = exceeds(options.max_literal_size, i - start) {
+                    return Err(ParseError::LiteralTooLarge { offset: start, length: i - start, max });
+                }
+                let word = &query[start..i];
+                let is_operator = depth > 0 && (word.eq_ignore_ascii_case("or") || word.eq_ignore_ascii_case("and"))
+                    && bytes[start - 1].is_ascii_whitespace()
+                    && bytes.get(i).map(u8::is_ascii_whitespace) == Some(true);
+                let is_not = depth > 0 && word.eq_ignore_ascii_case("not")
+                    && bytes[i..].iter().find(|b| !b.is_ascii_whitespace()) == Some(&b'(');
+                if is_operator || is_not {
+                    path_start = true;
+                    continue;
+                }
+                starts_segment = path_start;
+            },
+        }
+        path_start = false;
+        if starts_segment {
+            segments += 1;
+            if let Some(max) = exceeds(options.max_segments, segments) {
+                return Err(ParseError::TooManySegments { offset: start, max });
+            }
+        }
+    }
+    Ok(())
+}
+
+/// the offset right after the closing `quote` of a literal whose contents start at `i`.
+fn skip_quoted(bytes: &[u8], mut i: usize, quote: &[u8]) -> usize {
+    while i < bytes.len() {
+        if bytes[i] == b'\\' {
+            i += 2;
+        }
+        else if bytes[i..].starts_with(quote) {
+            return i + quote.len();
+        }
+        else {
+            i += 1;
+        }
+    }
+    bytes.len()
+}
+
+/// the offset right after the closing `}}` of a blob or clob whose contents start at `i`.
+fn skip_lob(bytes: &[u8], mut i: usize) -> usize {
+    while i < bytes.len() {
+        match bytes[i] {
+            b'}' if bytes[i..].starts_with(b"}}") => return i + 2,
+            b'\'' if bytes[i..].starts_with(b"'''") => i = skip_quoted(bytes, i + 3, b"'''"),
+            b'"' => i = skip_quoted(bytes, i + 1, b"\""),
+            _ => i += 1,
+        }
+    }
+    bytes.len()
+}
+
+/// the offset right after the unquoted key, annotation or literal continuing at `i`.
+fn skip_token(bytes: &[u8], mut i: usize) -> usize {
+    while i < bytes.len() && !b" \t\r\n\x0B\x0C/[]()|:=!<>\'\"{".contains(&bytes[i]) {
+        i += 1;
+    }
+    i
+}
+
 
 peg::parser!{
     pub grammar ionpath_parser() for str {
@@ -132,7 +281,9 @@ peg::parser!{
             = $("null" / "bool" / "int" / "float" / "decimal" / "timestamp"
               / "string" / "symbol" / "blob" / "clob" / "struct" / "list" / "sexp")
 
-        rule null_type() -> IonType = "." ty:typename()
+        rule null_type() -> IonType = "." ty:ion_type() { ty }
+
+        rule ion_type() -> IonType = ty:typename() !symbol_char()
         {
             match ty {
                 "null" => IonType::Null,
@@ -200,7 +351,7 @@ peg::parser!{
         pub rule float() -> Literal
             = s:($("-"? decimal_unsigned_int() decimal_frac()? float_exp()) / $(("+" / "-")? "inf") / $("nan") / $("NaN"))
         {?
-            s.replace("_", "").parse().map(|f| Literal::Float(f)).map_err(|_| "float")
+            s.replace("_", "").parse().map(Literal::Float).map_err(|_| "float")
         }
 
         pub rule decimal() -> Literal
@@ -229,7 +380,7 @@ peg::parser!{
         rule quoted_symbol() -> Literal
             = s:$("'" (symbol_text_allowed() / unicode_escape() / escape_seq())* "'")
         {?
-            unescape(&s[1..(s.len()-1)]).map(|s| Literal::Symbol(s))
+            unescape(&s[1..(s.len()-1)]).map(Literal::Symbol)
         }
 
         rule ident_symbol() -> Literal
@@ -238,6 +389,8 @@ peg::parser!{
             Literal::Symbol(s.to_string())
         }
 
+        rule symbol_char() = ['$' | '_' | 'a'..='z' | 'A'..='Z' | '0'..='9']
+
         pub rule symbol() -> Literal
             = quoted_symbol() / ident_symbol()
 
@@ -277,7 +430,7 @@ peg::parser!{
         pub rule string() -> Literal
             = s:(long_quoted_string() / quoted_string())
         {?
-            unescape(&s).map(|s| Literal::String(s))
+            unescape(&s).map(Literal::String)
         }
 
         // blob
@@ -285,9 +438,9 @@ peg::parser!{
         pub rule blob() -> Literal
             = s:$(ws() "{{" ws() base64_quartet()* base64_pad()? ws() "}}" ws())
         {?
-            let string = s.replace(&[' ', '\t', '\r', '\n', '\x0B', '\x0C'], "").replace("{{", "").replace("}}", "");
+            let string = s.replace([' ', '\t', '\r', '\n', '\x0B', '\x0C'], "").replace("{{", "").replace("}}", "");
             base64::engine::general_purpose::STANDARD.decode(&string)
-                .map(|b| Literal::Blob(b))
+                .map(Literal::Blob)
                 .or(Err("valid base64"))
         }
 
@@ -431,7 +584,7 @@ peg::parser!{
         }
 
         rule slice_closed_start() -> (Option<i32>, Option<i32>)
-            = a:$("-"? decimal_unsigned_int()) ws() ":" ws() b:$("-"? decimal_unsigned_int())? ws()
+            = a:$("-"? decimal_unsigned_int()) ws() ":" !ion_type() ws() b:$("-"? decimal_unsigned_int())? ws()
         {?
             if let Ok(ia) = a.parse() {
                 if let Some(b) = b {
@@ -467,7 +620,39 @@ peg::parser!{
             }
         }
 
-        rule key() -> Key = key_slice() / key_literal()
+        // a regular expression, checked here so that invalid ones are a syntax error
+        rule regex() -> String
+            = "~" ws() s:string()
+        {?
+            match s {
+                Literal::String(s) if Regex::new(&s).is_ok() => Ok(s),
+                _ => Err("valid regular expression"),
+            }
+        }
+
+        rule key_regex() -> Key = r:regex() { Key::Regex(r) }
+
+        rule key_single() -> Key = key_regex() / key_slice() / key_literal()
+
+        rule key_choice_list() -> Key
+            = "(" ws() first:key_single() rest:(key_choice()*) ws() ")"
+        {
+            if rest.is_empty() {
+                first
+            }
+            else {
+                let mut keys = vec![first];
+                for k in rest {
+                    keys.push(k);
+                }
+                Key::Choice(keys)
+            }
+        }
+
+        rule key_choice() -> Key
+            = ws() "|" ws() k:key_single() { k }
+
+        rule key() -> Key = key_choice_list() / key_single()
 
         rule cmp() -> CompareOp
             = s:$("==" / "=" / "!=" / ">=" / "<=" / ">" / "<")
@@ -483,88 +668,219 @@ peg::parser!{
             }
         }
 
-        rule annotation_single() -> Vec<String>
-            = sym:(symbol() / string()) ws() "::" ws()
+        rule annotation_key() -> AnnotationKey
+            = r:regex() { AnnotationKey::Regex(r) }
+            / sym:(symbol() / string())
         {
-            vec![match sym {
-                Literal::Symbol(s) => s,
-                Literal::String(s) => s,
+            match sym {
+                Literal::Symbol(s) => AnnotationKey::Name(s),
+                Literal::String(s) => AnnotationKey::Name(s),
                 _ => unreachable!()
-            }]
+            }
         }
 
-        rule annotation_choice_list() -> Vec<String>
-            = "(" ws() first:(symbol() / string()) rest:(annotation_choice()*) ws() ")" ws() "::" ws()
+        rule annotation_single() -> Vec<AnnotationKey>
+            = ann:annotation_key() ws() "::" ws()
+        {
+            vec![ann]
+        }
+
+        rule annotation_choice_list() -> Vec<AnnotationKey>
+            = "(" ws() first:annotation_key() rest:(annotation_choice()*) ws() ")" ws() "::" ws()
         {
             let mut results = Vec::new();
-            results.push(match first {
-                Literal::Symbol(s) => s,
-                Literal::String(s) => s,
-                _ => unreachable!()
-            });
-            for sy in rest {
-                results.push(sy);
+            results.push(first);
+            for ann in rest {
+                results.push(ann);
             }
             results
         }
 
-        rule annotation_choice() -> String
-            = ws() "|" ws() sy:(symbol() / string())
+        rule annotation_choice() -> AnnotationKey
+            = ws() "|" ws() ann:annotation_key() { ann }
+
+        // `not` binds most tightly, then `and`, then `or`
+        rule predicate() -> PredicateExpr = ws() "[" e:pred_or() ws() "]" { e }
+
+        rule pred_or() -> PredicateExpr = exprs:(pred_and() ++ (ws() keyword("or")))
         {
-            match sy {
-                Literal::Symbol(s) => s,
-                Literal::String(s) => s,
-                _ => unreachable!()
-            }
+            let mut exprs = exprs;
+            if exprs.len() == 1 { exprs.remove(0) } else { PredicateExpr::Or(exprs) }
         }
 
-        rule predicate_OR_list() -> Vec<Predicate>
-            = ws() "[" first:(pred_cmp() / pred_single_path()) rest:(or_predicate())* "]"
+        rule pred_and() -> PredicateExpr = exprs:(pred_unary() ++ (ws() keyword("and")))
         {
-            let mut all = vec![first];
-            for p in rest {
-                all.push(p);
-            }
-            all
+            let mut exprs = exprs;
+            if exprs.len() == 1 { exprs.remove(0) } else { PredicateExpr::And(exprs) }
         }
 
+        // a predicate comes before a parenthesized expression, so that `[(a|b) = 1]` is a path
+        rule pred_unary() -> PredicateExpr
+            = ws() keyword("not") ws() "(" e:pred_or() ws() ")" { PredicateExpr::Not(Box::new(e)) }
+            / p:pred_single() { PredicateExpr::Predicate(p) }
+            / ws() "(" e:pred_or() ws() ")" { e }
+
+        rule pred_single() -> Predicate
+            = pred_cmp() / pred_type() / pred_condition() / pred_call() / pred_single_path()
+
         rule pred_single_path() -> Predicate =  ws() p:path()  {
             Predicate::Path(Box::new(p))
         }
 
-        rule pred_cmp() -> Predicate = ws() p:path()? ws() c:cmp()  ws() l:literal() {
+        // only if the literal ends the predicate, so that `[a > 3 - 1]` is a condition
+        rule pred_cmp() -> Predicate = ws() p:path()? ws() c:cmp()  ws() l:literal() &pred_end() {
             Predicate::Compare {
-                path: p.map(|p| Box::new(p)),
+                path: p.map(Box::new),
                 op: c,
                 value: l
             }
         }
 
-        rule or_predicate() -> Predicate
-            = ws() ("or"/"OR"/"oR"/"Or") ws() p:(pred_cmp() / pred_single_path())  { p }
+        rule pred_condition() -> Predicate = ws() lhs:expr() ws() op:cmp() ws() rhs:condition_rhs() ws() {
+            Predicate::Condition { lhs, op, rhs }
+        }
+
+        // `lower(s) = 'active'`, where the quoted symbol is a literal as in `[s = 'active']`
+        rule condition_rhs() -> Expr
+            = s:quoted_symbol() &pred_end() { Expr::Literal(s) }
+            / expr()
+
+        // `type() = struct`, where the bare type name isn't a path
+        rule pred_type() -> Predicate
+            = ws() lhs:call() ws() op:cmp() ws() ty:ion_type() &pred_end()
+        {?
+            match (lhs, op) {
+                (lhs @ Expr::Call(Function::Type, _), op @ (CompareOp::Equal | CompareOp::NotEqual)) =>
+                    Ok(Predicate::Condition { lhs, op, rhs: Expr::Literal(Literal::Symbol(ty.to_string())) }),
+                _ => Err("type()"),
+            }
+        }
+
+        rule pred_call() -> Predicate = ws() call:call() ws() {?
+            match call {
+                Expr::Call(function, args) if function.is_test() => Ok(Predicate::Call(function, args)),
+                _ => Err("a function returning a boolean"),
+            }
+        }
 
-        rule first_segment() -> (Segment, bool /* is_absolute */)
-            = first:"/"? second:"/"? ws() annotation_lists:(annotation_choice_list() / annotation_single())* k:key() pred_lists:(predicate_OR_list()*) ws()
+        rule pred_end() = ws() ("]" / ")" / keyword("or") / keyword("and"))
+
+        // `and`, `or` and `not`, in any case
+        rule keyword(name: &'static str) = word:$(['a'..='z' | 'A'..='Z']+) !symbol_char()
+        {?
+            if word.eq_ignore_ascii_case(name) { Ok(()) } else { Err(name) }
+        }
+
+        // expressions
+
+        // the alternatives of a predicate start over from the same position, and would otherwise
+        // parse the same path or expression again for each, at every level of nesting
+        #[cache]
+        rule expr() -> Expr = precedence!{
+            lhs:(@) ws() "+" ws() rhs:@ { Expr::Arithmetic(Box::new(lhs), ArithOp::Add, Box::new(rhs)) }
+            lhs:(@) ws() "-" ws() rhs:@ { Expr::Arithmetic(Box::new(lhs), ArithOp::Subtract, Box::new(rhs)) }
+            --
+            e:expr_atom() { e }
+        }
+
+        // symbols, quoted or not, are paths, as on the left of a comparison
+        rule expr_atom() -> Expr
+            = call()
+            / lit:literal() {? match lit {
+                Literal::Symbol(_) => Err("expression"),
+                lit => Ok(Expr::Literal(lit)),
+            } }
+            / p:path() { Expr::Path(Box::new(p)) }
+            / "(" ws() e:expr() ws() ")" { e }
+
+        #[cache]
+        rule call() -> Expr
+            = name:$(['a'..='z' | '_']+) ws() "(" ws() args:(expr() ** (ws() "," ws())) ws() ")"
+        {?
+            match Function::from_name(name) {
+                Some(function) if function.arity().contains(&args.len()) => Ok(Expr::Call(function, args)),
+                Some(_) => Err("the right number of arguments"),
+                None => Err("function"),
+            }
+        }
+
+        rule parent_step() -> Segment
+            = ".." predicates:(predicate()*) ws()
+        {
+            Segment {
+                recursive: false,
+                axis: Axis::Parent,
+                annotation_lists: Vec::new(),
+                key: Key::Symbol("*".to_string()),
+                name_matching: NameMatching::default(),
+                types: Vec::new(),
+                predicates,
+            }
+        }
+
+        rule axis() -> Axis
+            = "ancestor::" { Axis::Ancestor }
+            / "following-sibling::" { Axis::FollowingSibling }
+            / "preceding-sibling::" { Axis::PrecedingSibling }
+
+        // `descendant::key` is another way of writing `//key`. axis names take precedence over
+        // annotations with the same name, which can still be matched by quoting them: `'ancestor'::*`
+        rule descendant_axis() = "descendant::"
+
+        rule segment_body(recursive: bool, axis: Axis) -> Segment
+            = annotation_lists:(annotation_choice_list() / annotation_single())* k:key() matching:name_matching()?
+              types:type_filter()? predicates:(predicate()*) ws()
         {
-            (Segment {
-                recursive: first.is_some() && second.is_some(),
+            Segment {
+                recursive,
+                axis,
                 annotation_lists,
                 key: k,
-                predicate_lists: pred_lists,
-            }, first.is_some())
+                name_matching: matching.unwrap_or_default(),
+                types: types.unwrap_or_default(),
+                predicates,
+            }
+        }
+
+        // `:int` or `:(int|float)`, not to be confused with annotations (`::`)
+        rule type_filter() -> Vec<IonType>
+            = ":" ty:ion_type() { vec![ty] }
+            / ":" "(" ws() types:(ion_type() ++ (ws() "|" ws())) ws() ")" { types }
+
+        // `~i` ignores case, `~n` compares normalized names, `~in` does both
+        rule name_matching() -> NameMatching
+            = "~" flags:$(['i' | 'n']+)
+        {?
+            let mut matching = NameMatching::new();
+            for flag in flags.chars() {
+                match flag {
+                    'i' if !matching.ignore_case => matching.ignore_case = true,
+                    'n' if !matching.normalize => matching.normalize = true,
+                    _ => return Err("each name matching flag only once"),
+                }
+            }
+            Ok(matching)
+        }
+
+        rule first_segment() -> (Segment, bool /* is_absolute */)
+            = first:"/"? ws() seg:parent_step() { (seg, first.is_some()) }
+            / first:"/"? ws() axis:axis() ws() seg:segment_body(false, axis) { (seg, first.is_some()) }
+            / first:"/"? second:"/"? ws() descendant:(descendant_axis() ws())? !axis()
+              seg:segment_body(first.is_some() && second.is_some() || descendant.is_some(), Axis::Child)
+        {
+            (seg, first.is_some())
         }
 
         rule other_segment() -> (Segment, bool /* is_absolute */)
-            = "/" second:"/"? ws() annotation_lists:(annotation_choice_list() / annotation_single())* k:key() pred_lists:(predicate_OR_list()*) ws()
+            = "/" ws() seg:parent_step() { (seg, true) }
+            / "/" ws() axis:axis() ws() seg:segment_body(false, axis) { (seg, true) }
+            / "/" second:"/"? ws() descendant:(descendant_axis() ws())? !axis()
+              seg:segment_body(second.is_some() || descendant.is_some(), Axis::Child)
         {
-            (Segment {
-                recursive: second.is_some(),
-                annotation_lists,
-                key: k,
-                predicate_lists: pred_lists,
-            }, true)
+            (seg, true)
         }
 
+        #[cache]
         pub rule path() -> Path = first:first_segment() rest:(other_segment()*) {
             let mut segments = VecDeque::new();
             segments.push_back(first.0);
@@ -573,5 +889,7 @@ peg::parser!{
             }
             Path { absolute: first.1, segments }
         }
+
+        pub rule union() -> PathUnion = ws() paths:(path() ++ ("|" ws())) { PathUnion::new(paths) }
     }
 }
\ No newline at end of file
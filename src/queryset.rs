@@ -0,0 +1,238 @@
+use std::collections::HashSet;
+use std::rc::Rc;
+use ion_rs::element::Element;
+use crate::{Axis, CompiledPath, EvalContext, EvalError, EvalOptions, Location, Path};
+use crate::compiled::{reaches_once, run_segments, CompiledSegment};
+use crate::location::Node;
+
+
+/// A single result of evaluating a [`QuerySet`].
+#[derive(Debug, Clone, PartialEq)]
+pub struct QueryMatch<'a> {
+    /// index of the matching query, in the order the queries were added to the set.
+    pub query: usize,
+    pub location: Location,
+    pub element: &'a Element,
+}
+
+/// Several paths, evaluated together in a single traversal of the document.
+///
+/// Queries that start with the same segments share them: each shared segment is only matched
+/// once per element, no matter how many queries it is part of. The document itself is walked
+/// once, with every query that can still match following along.
+///
+/// For every query, the set returns the same matches as evaluating that query's [`Path`] on
+/// its own, but all results come in document order (pre-order, depth-first). This differs from
+/// [`Path::match_ref`] only for slices that select items in reverse order.
+///
+/// A traversal that only moves down can't follow segments like `..`. The rest of a query from
+/// such a segment on is evaluated separately from every element the query reached before it,
+/// and its matches are reported right after that element. Like with [`Path`], each segment
+/// that doesn't move to children reaches an element only once: if it was already reached
+/// from an earlier element, it isn't followed again.
+#[derive(Debug, Clone, Default)]
+pub struct QuerySet {
+    paths: Vec<Path>,
+    start: Continuations,
+}
+
+/// A segment shared by one or more queries that start with the same segments.
+#[derive(Debug, Clone)]
+struct QueryState {
+    compiled: CompiledSegment,
+    then: Continuations,
+}
+
+/// How queries continue from an element that matched all segments so far.
+#[derive(Debug, Clone, Default)]
+struct Continuations {
+    /// queries without any further segments, which match the element itself.
+    queries: Vec<usize>,
+    /// queries whose next segment doesn't move to children, with their remaining segments.
+    tails: Vec<(usize, CompiledPath)>,
+    /// next segments that move to children.
+    states: Vec<QueryState>,
+}
+
+impl QuerySet {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// adds `path` to the set, returning the index its matches are tagged with.
+    pub fn add(&mut self, path: Path) -> usize {
+        let query = self.paths.len();
+        let mut level = &mut self.start;
+        let mut tail = None;
+        for (k, seg) in path.segments.iter().enumerate() {
+            if seg.axis != Axis::Child {
+                tail = Some(CompiledPath {
+                    absolute: false,
+                    segments: path.segments.range(k..).map(CompiledSegment::new).collect(),
+                });
+                break;
+            }
+            let i = match level.states.iter().position(|state| &state.compiled.segment == seg) {
+                Some(i) => i,
+                None => {
+                    level.states.push(QueryState {
+                        compiled: CompiledSegment::new(seg),
+                        then: Continuations::default(),
+                    });
+                    level.states.len() - 1
+                }
+            };
+            level = &mut level.states[i].then;
+        }
+        match tail {
+            Some(tail) => level.tails.push((query, tail)),
+            None => level.queries.push(query),
+        }
+        self.paths.push(path);
+        query
+    }
+
+    pub fn with(mut self, path: Path) -> Self {
+        self.add(path);
+        self
+    }
+
+    /// the query with the given index.
+    pub fn get(&self, query: usize) -> Option<&Path> {
+        self.paths.get(query)
+    }
+
+    pub fn len(&self) -> usize {
+        self.paths.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.paths.is_empty()
+    }
+
+    /// evaluates every query in the set against `root_element`.
+    pub fn match_ref<'a>(&self, root_element: &'a Element) -> Vec<QueryMatch<'a>> {
+        let ctx = EvalContext::new(std::slice::from_ref(root_element));
+        self.match_element_in(&ctx, root_element)
+    }
+
+    /// evaluates every query in the set starting at `element`. absolute paths inside predicates
+    /// start from the context's root(s). the context's options are ignored, see
+    /// [`QuerySet::try_match_element_in`].
+    pub fn match_element_in<'a>(&self, ctx: &EvalContext<'a>, element: &'a Element) -> Vec<QueryMatch<'a>> {
+        // evaluating with the default options never fails
+        self.try_match_element_in(&ctx.lenient(), element).unwrap_or_default()
+    }
+
+    /// like [`QuerySet::match_ref`], evaluating with the given options.
+    pub fn try_match_ref<'a>(&self, root_element: &'a Element, options: EvalOptions) -> Result<Vec<QueryMatch<'a>>, EvalError> {
+        let ctx = EvalContext::new(std::slice::from_ref(root_element)).with_options(options);
+        self.try_match_element_in(&ctx, root_element)
+    }
+
+    /// like [`QuerySet::match_element_in`], evaluating with the context's options.
+    pub fn try_match_element_in<'a>(&self, ctx: &EvalContext<'a>, element: &'a Element) -> Result<Vec<QueryMatch<'a>>, EvalError> {
+        let root = Rc::new(Node::root(element, 0));
+        let mut results = Vec::new();
+        let mut reached = HashSet::new();
+        self.start.matched(ctx, &root, &mut reached, &mut results)?;
+        let states: Vec<&QueryState> = self.start.states.iter().collect();
+        visit(ctx, &root, &states, &mut reached, &mut results)?;
+        Ok(results)
+    }
+}
+
+impl FromIterator<Path> for QuerySet {
+    fn from_iter<I: IntoIterator<Item = Path>>(paths: I) -> Self {
+        let mut set = QuerySet::new();
+        for path in paths {
+            set.add(path);
+        }
+        set
+    }
+}
+
+/// the elements each segment of a tail that doesn't move to children has reached so far, by
+/// query and index in the tail.
+type Reached = HashSet<(usize, usize, *const Element)>;
+
+impl Continuations {
+    /// reports the queries that end at `node`, and evaluates the tails starting there.
+    fn matched<'a>(&self, ctx: &EvalContext<'a>, node: &Node<'a>, reached: &mut Reached, results: &mut Vec<QueryMatch<'a>>) -> Result<(), EvalError> {
+        if !self.queries.is_empty() {
+            ctx.add_results(self.queries.len())?;
+            let location = node.location();
+            results.extend(self.queries.iter()
+                .map(|&query| QueryMatch { query, location: location.clone(), element: node.element }));
+        }
+        for (query, tail) in self.tails.iter() {
+            let mut context = vec![node.clone()];
+            for (i, seg) in tail.segments.iter().enumerate() {
+                context = run_segments(ctx, std::slice::from_ref(seg), context, None)?;
+                if reaches_once(&tail.segments, i) {
+                    context.retain(|found| reached.insert((*query, i, found.element as *const Element)));
+                }
+            }
+            ctx.add_results(context.len())?;
+            results.extend(context.into_iter()
+                .map(|found| QueryMatch { query: *query, location: found.location(), element: found.element }));
+        }
+        Ok(())
+    }
+}
+
+
+/// matches the children of `parent` (and their descendants) against every active state.
+fn visit<'a>(ctx: &EvalContext<'a>, parent: &Rc<Node<'a>>, states: &[&QueryState], reached: &mut Reached, results: &mut Vec<QueryMatch<'a>>) -> Result<(), EvalError> {
+    if states.is_empty() {
+        return Ok(());
+    }
+    let children: Vec<Node<'a>> = if let Some(sequence) = parent.element.as_sequence() {
+        sequence.elements().enumerate()
+            .map(|(i, child)| Node::child(parent, child, i, None))
+            .collect()
+    }
+    else if let Some(st) = parent.element.as_struct() {
+        st.fields().enumerate()
+            .map(|(i, (name, child))| Node::child(parent, child, i, Some(name.text().unwrap_or("$0"))))
+            .collect()
+    }
+    else {
+        return Ok(());
+    };
+    // which items each state's key selects only depends on the length of the sequence
+    let indices: Vec<Vec<usize>> = match parent.element.as_sequence() {
+        Some(sequence) => states.iter().map(|state| state.compiled.key.sequence_indices(sequence.len())).collect(),
+        None => Vec::new(),
+    };
+
+    for child in children {
+        ctx.visit(&child)?;
+        // a state can be reached both by looking further down and by matching the one before it
+        // (e.g. `//x` in `//n//x` below nested `n`s), but is only followed once
+        let mut next_states: Vec<&QueryState> = Vec::new();
+        for (i, state) in states.iter().enumerate() {
+            let seg = &state.compiled;
+            let matching = seg.name_matching(ctx.options());
+            if seg.recursive && !next_states.iter().any(|s| std::ptr::eq(*s, *state)) {
+                // keep looking for matches further down
+                next_states.push(state);
+            }
+            let key_matches = match child.field {
+                Some(name) => seg.key.matches_field_name(name, matching),
+                None => indices[i].contains(&child.position),
+            };
+            if key_matches && seg.match_annotations(child.element.annotations(), matching)
+                && seg.match_type(child.element.ion_type()) && seg.match_predicates(ctx, &child)? {
+                state.then.matched(ctx, &child, reached, results)?;
+                for then in state.then.states.iter() {
+                    if !next_states.iter().any(|s| std::ptr::eq(*s, then)) {
+                        next_states.push(then);
+                    }
+                }
+            }
+        }
+        visit(ctx, &Rc::new(child), &next_states, reached, results)?;
+    }
+    Ok(())
+}
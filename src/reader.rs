@@ -0,0 +1,265 @@
+use ion_rs::{IonReader, IonResult, IonType, StreamItem, Symbol};
+use ion_rs::element::{Annotations, Element, Sequence, Struct, Value};
+use crate::{Axis, CompiledPath, EvalContext, EvalOptions, Path, StreamError, StreamMatch};
+use crate::compiled::{reaches_once, CompiledPredicate, CompiledPredicateExpr};
+use crate::location::Node;
+
+
+impl CompiledPath {
+    /// evaluates this path against every top-level value read from `reader`, without reading
+    /// the whole stream into memory.
+    ///
+    /// the reader only steps into containers that can contain a match, and only the matches
+    /// themselves are read into `Element`s. a few things need more of the document at once,
+    /// in which case the smallest element that covers them is read and evaluated in memory:
+    ///   - predicates are checked against the (fully read) candidate element
+    ///   - keys that count from the end of a sequence (`/-1`, `/:-2`) or select items in reverse
+    ///     order (`/3:0:-1`) read the whole sequence
+    ///   - a recursive (`//`) match is read along with everything nested inside of it
+    ///   - paths that move up or sideways (`..`), or contain absolute paths in predicates,
+    ///     read each top-level value as a whole
+    ///
+    /// since the stream is never held in memory, absolute paths inside predicates are evaluated
+    /// against the top-level value they appear in, rather than against the whole stream as
+    /// [`CompiledPath::match_stream`] does.
+    pub fn match_reader<R>(&self, reader: &mut R) -> IonResult<Vec<StreamMatch>>
+        where R: IonReader<Item = StreamItem, Symbol = Symbol> + ?Sized
+    {
+        match self.try_match_reader(reader, EvalOptions::default()) {
+            Ok(results) => Ok(results),
+            Err(StreamError::Read(error)) => Err(error),
+            // evaluating with the default options never fails
+            Err(StreamError::Eval(_)) => Ok(Vec::new()),
+        }
+    }
+
+    /// like [`CompiledPath::match_reader`], evaluating with the given options. the limits
+    /// apply to the whole stream, but only count the work done on elements that were read
+    /// into memory: the values the reader skips over aren't visited.
+    pub fn try_match_reader<R>(&self, reader: &mut R, options: EvalOptions) -> Result<Vec<StreamMatch>, StreamError>
+        where R: IonReader<Item = StreamItem, Symbol = Symbol> + ?Sized
+    {
+        let mut eval = ReaderEval {
+            path: self,
+            suffixes: (0..self.segments.len())
+                .map(|k| CompiledPath { absolute: false, segments: self.segments[k..].to_vec() })
+                .collect(),
+            ctx: EvalContext::new(&[]).with_options(options),
+            index: 0,
+            results: Vec::new(),
+        };
+        // nested recursive segments can reach the same element through several of its
+        // ancestors, which only evaluating the whole value at once notices
+        let needs_root = self.needs_whole_values()
+            || (0..self.segments.len()).any(|k| reaches_once(&self.segments, k));
+        while reader.next()? != StreamItem::Nothing {
+            if needs_root || self.segments.is_empty() {
+                let value = read_current(reader)?;
+                eval.eval_in_memory(&value, 0)?;
+            }
+            else {
+                eval.eval_at(reader, 0)?;
+            }
+            eval.index += 1;
+        }
+        Ok(eval.results)
+    }
+
+    /// true if this path can look outside of the elements it matched, i.e. if any segment
+    /// (including those in predicates, at any depth) doesn't move to children, or any
+    /// predicate contains an absolute path or depends on the position of an element.
+    pub(crate) fn needs_whole_values(&self) -> bool {
+        self.segments.iter().any(|seg| seg.axis != Axis::Child)
+            || self.segments.iter()
+                .flat_map(|seg| seg.predicates.iter().flat_map(CompiledPredicateExpr::predicates))
+                .any(|pred| match pred {
+                    CompiledPredicate::Path(p) | CompiledPredicate::Compare { path: Some(p), .. } => {
+                        p.absolute || p.needs_whole_values()
+                    },
+                    CompiledPredicate::Compare { path: None, .. } => false,
+                    CompiledPredicate::Condition { lhs, rhs, .. } => lhs.looks_outside() || rhs.looks_outside(),
+                    CompiledPredicate::Test(expr) => expr.looks_outside(),
+                })
+    }
+}
+
+impl Path {
+    /// see [`CompiledPath::match_reader`].
+    pub fn match_reader<R>(&self, reader: &mut R) -> IonResult<Vec<StreamMatch>>
+        where R: IonReader<Item = StreamItem, Symbol = Symbol> + ?Sized
+    {
+        self.compile().match_reader(reader)
+    }
+
+    /// see [`CompiledPath::try_match_reader`].
+    pub fn try_match_reader<R>(&self, reader: &mut R, options: EvalOptions) -> Result<Vec<StreamMatch>, StreamError>
+        where R: IonReader<Item = StreamItem, Symbol = Symbol> + ?Sized
+    {
+        self.compile().try_match_reader(reader, options)
+    }
+}
+
+
+struct ReaderEval<'p> {
+    path: &'p CompiledPath,
+    /// `suffixes[k]` is a relative path made of `path.segments[k..]`, used to continue
+    /// evaluation in memory once an element has been read.
+    suffixes: Vec<CompiledPath>,
+    /// the options and counts shared by the evaluation of every element read into memory.
+    ctx: EvalContext<'static>,
+    /// index of the current top-level value.
+    index: usize,
+    results: Vec<StreamMatch>,
+}
+
+impl<'p> ReaderEval<'p> {
+    /// evaluates `path.segments[k..]` starting at the reader's current value.
+    fn eval_at<R>(&mut self, reader: &mut R, k: usize) -> Result<(), StreamError>
+        where R: IonReader<Item = StreamItem, Symbol = Symbol> + ?Sized
+    {
+        let seg = &self.path.segments[k];
+        let is_sequence = match reader.current() {
+            StreamItem::Value(IonType::List | IonType::SExp) => true,
+            StreamItem::Value(IonType::Struct) => false,
+            // scalars and nulls have no children to match
+            _ => return Ok(()),
+        };
+        if is_sequence && seg.key.index_matches(0).is_none() {
+            // the key depends on the length of the sequence
+            let value = read_current(reader)?;
+            return self.eval_in_memory(&value, k);
+        }
+        reader.step_in()?;
+        let mut i = 0;
+        while reader.next()? != StreamItem::Nothing {
+            let matched = if is_sequence {
+                seg.key.index_matches(i) == Some(true)
+            }
+            else {
+                seg.key.matches_field_name(reader.field_name()?.text().unwrap_or("$0"), seg.name_matching(self.ctx.options()))
+            };
+            if matched {
+                self.visit_match(reader, k)?;
+            }
+            else if seg.recursive {
+                self.eval_at(reader, k)?;
+            }
+            i += 1;
+        }
+        Ok(reader.step_out()?)
+    }
+
+    /// handles the reader's current value, which matches the key of `path.segments[k]`.
+    fn visit_match<R>(&mut self, reader: &mut R, k: usize) -> Result<(), StreamError>
+        where R: IonReader<Item = StreamItem, Symbol = Symbol> + ?Sized
+    {
+        let seg = &self.path.segments[k];
+        let is_last = k + 1 == self.path.segments.len();
+        let annotations = read_annotations(reader)?;
+        let annotations_match = seg.match_annotations(&annotations, seg.name_matching(self.ctx.options()))
+            && reader.ion_type().is_some_and(|ty| seg.match_type(ty));
+
+        if !annotations_match {
+            // still search inside it for nested matches of a recursive segment
+            return if seg.recursive { self.eval_at(reader, k) } else { Ok(()) };
+        }
+        if !seg.recursive && !is_last && seg.predicates.is_empty() {
+            // nothing to check on this element itself, keep streaming
+            return self.eval_at(reader, k + 1);
+        }
+
+        let value = read_current(reader)?;
+        let ctx = self.ctx.with_roots(std::slice::from_ref(&value));
+        if seg.match_predicates(&ctx, &Node::root(&value, 0))? {
+            if is_last {
+                ctx.add_results(1)?;
+                self.results.push(StreamMatch { index: self.index, element: value.clone() });
+            }
+            else {
+                self.eval_in_memory(&value, k + 1)?;
+            }
+        }
+        if seg.recursive {
+            self.eval_in_memory(&value, k)?;
+        }
+        Ok(())
+    }
+
+    /// evaluates `path.segments[k..]` starting at `element`.
+    fn eval_in_memory(&mut self, element: &Element, k: usize) -> Result<(), StreamError> {
+        let ctx = self.ctx.with_roots(std::slice::from_ref(element));
+        let suffix = match self.suffixes.get(k) {
+            Some(suffix) => suffix,
+            None => {
+                // no segments left, the element itself is the match
+                ctx.add_results(1)?;
+                self.results.push(StreamMatch { index: self.index, element: element.clone() });
+                return Ok(());
+            }
+        };
+        let mut matches = suffix.iter_in(&ctx, element);
+        while let Some(found) = matches.try_next()? {
+            self.results.push(StreamMatch { index: self.index, element: found.clone() });
+        }
+        Ok(())
+    }
+}
+
+
+fn read_annotations<R>(reader: &R) -> IonResult<Annotations>
+    where R: IonReader<Item = StreamItem, Symbol = Symbol> + ?Sized
+{
+    if !reader.has_annotations() {
+        return Ok(Annotations::empty());
+    }
+    let annotations: IonResult<Vec<Symbol>> = reader.annotations().collect();
+    Ok(Annotations::from(annotations?))
+}
+
+/// reads the reader's current value (and any children) into an `Element`.
+fn read_current<R>(reader: &mut R) -> IonResult<Element>
+    where R: IonReader<Item = StreamItem, Symbol = Symbol> + ?Sized
+{
+    let annotations = read_annotations(reader)?;
+    let value = match reader.current() {
+        StreamItem::Nothing => unreachable!("read_current() called without a current value"),
+        StreamItem::Null(ion_type) => Value::Null(ion_type),
+        StreamItem::Value(ion_type) => match ion_type {
+            IonType::Null => unreachable!("non-null value had IonType::Null"),
+            IonType::Bool => Value::Bool(reader.read_bool()?),
+            IonType::Int => Value::Int(reader.read_int()?),
+            IonType::Float => Value::Float(reader.read_f64()?),
+            IonType::Decimal => Value::Decimal(reader.read_decimal()?),
+            IonType::Timestamp => Value::Timestamp(reader.read_timestamp()?),
+            IonType::Symbol => Value::Symbol(reader.read_symbol()?),
+            IonType::String => Value::String(reader.read_string()?),
+            IonType::Clob => Value::Clob(reader.read_clob()?.into()),
+            IonType::Blob => Value::Blob(reader.read_blob()?.into()),
+            IonType::List => Value::List(read_sequence(reader)?),
+            IonType::SExp => Value::SExp(read_sequence(reader)?),
+            IonType::Struct => {
+                let mut fields = Vec::new();
+                reader.step_in()?;
+                while reader.next()? != StreamItem::Nothing {
+                    let name = reader.field_name()?;
+                    fields.push((name, read_current(reader)?));
+                }
+                reader.step_out()?;
+                Value::Struct(Struct::from_iter(fields))
+            },
+        },
+    };
+    Ok(Element::from(value).with_annotations(annotations))
+}
+
+fn read_sequence<R>(reader: &mut R) -> IonResult<Sequence>
+    where R: IonReader<Item = StreamItem, Symbol = Symbol> + ?Sized
+{
+    let mut children = Vec::new();
+    reader.step_in()?;
+    while reader.next()? != StreamItem::Nothing {
+        children.push(read_current(reader)?);
+    }
+    reader.step_out()?;
+    Ok(children.into())
+}
@@ -1,49 +1,116 @@
-use ion_rs::element::{Element, Sequence, Struct};
-use num::ToPrimitive;
-use crate::{Key, Predicate};
+use std::rc::Rc;
+use ion_rs::IonType;
+use ion_rs::element::Element;
+use crate::{EvalContext, Key, NameMatching, PredicateExpr};
+use crate::compiled::{CompiledSegment, KeyMatcher, KeyMatches};
+use crate::location::Node;
 
 
+/// The direction a segment moves in from each element of its context.
+///
+/// Axes other than `Child` match the key against the name (or index) an element has in its
+/// parent. The element evaluation started at has no parent, so it only matches `*`.
+/// `descendant::key` is the same as `//key`, i.e. a recursive `Child` segment.
+///
+/// A segment with an axis other than `Child` returns each element only once, in document
+/// order, even if it's reached from more than one element.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Axis {
+    /// children (or, for recursive segments, all descendants) matching the key.
+    #[default]
+    Child,
+    /// the parent, written `..`. the parent of the element evaluation started at is outside
+    /// of the document, so it has no parent.
+    Parent,
+    /// `ancestor::key`, all ancestors matching the key.
+    Ancestor,
+    /// `following-sibling::key`, siblings after the element in its parent struct or sequence.
+    FollowingSibling,
+    /// `preceding-sibling::key`, siblings before the element in its parent struct or sequence.
+    PrecedingSibling,
+}
+
+/// An annotation a segment requires, as written in an annotation list.
+#[derive(Debug, Clone, PartialEq)]
+pub enum AnnotationKey {
+    /// `A::`, exactly this annotation.
+    Name(String),
+    /// `~"v[0-9]+"::`, annotations the regular expression matches as a whole.
+    Regex(String),
+}
+
+impl From<String> for AnnotationKey {
+    fn from(name: String) -> Self {
+        AnnotationKey::Name(name)
+    }
+}
+
+impl From<&str> for AnnotationKey {
+    fn from(name: &str) -> Self {
+        AnnotationKey::Name(name.to_string())
+    }
+}
+
 #[derive(Debug, Clone, PartialEq)]
 pub struct Segment {
     pub recursive: bool,
-    pub annotation_lists: Vec<Vec<String>>,
+    pub axis: Axis,
+    pub annotation_lists: Vec<Vec<AnnotationKey>>,
     pub key: Key,
-    pub predicate_lists: Vec<Vec<Predicate>>,
+    /// how the key and annotations are compared with names, e.g. `~i` in `/userid~i`.
+    pub name_matching: NameMatching,
+    /// `:int` or `:(int|float)` in `/*:int`, the types matching elements can have (typed nulls
+    /// included). any type if empty.
+    pub types: Vec<IonType>,
+    /// one for each `[...]`, which all have to match.
+    pub predicates: Vec<PredicateExpr>,
 }
 
 impl Segment {
     pub fn new(recursive: bool, key: Key) -> Self {
         Segment {
             recursive,
+            axis: Axis::Child,
             annotation_lists: Vec::new(),
             key,
-            predicate_lists: Vec::new(),
+            name_matching: NameMatching::default(),
+            types: Vec::new(),
+            predicates: Vec::new(),
         }
     }
 
-    pub fn with_annotation_list(mut self, list: Vec<String>) -> Self {
+    /// a `..` segment.
+    pub fn parent() -> Self {
+        Segment::new(false, Key::Symbol("*".to_string())).with_axis(Axis::Parent)
+    }
+
+    pub fn with_axis(mut self, axis: Axis) -> Self {
+        self.axis = axis;
+        self
+    }
+
+    pub fn with_annotation_list(mut self, list: Vec<AnnotationKey>) -> Self {
         self.annotation_lists.push(list);
         self
     }
 
-    pub fn with_predicate_list(mut self, list: Vec<Predicate>) -> Self {
-        self.predicate_lists.push(list);
+    pub fn with_name_matching(mut self, matching: NameMatching) -> Self {
+        self.name_matching = matching;
+        self
+    }
+
+    pub fn with_types(mut self, types: Vec<IonType>) -> Self {
+        self.types = types;
+        self
+    }
+
+    pub fn with_predicate(mut self, pred: PredicateExpr) -> Self {
+        self.predicates.push(pred);
         self
     }
 
     pub fn match_annotations(&self, elem: &Element) -> bool {
-        // must match ALL lists, where each list must match ANY annotation
-        for ann_list in self.annotation_lists.iter() {
-            let mut matched_option = false;
-            for option in ann_list.iter() {
-                if elem.annotations().contains(option) {
-                    matched_option = true;
-                    break;
-                }
-            }
-            if !matched_option { return false; }
-        }
-        true
+        CompiledSegment::new(self).match_annotations(elem.annotations(), self.name_matching)
     }
 
     /// input: a single element of any type. may be a sequence, struct, or value.
@@ -52,95 +119,37 @@ impl Segment {
     ///         for structs: values of fields that match the key
     ///         for values: never matches (TODO: does it ever?)
     pub fn match_key(&self, element: &Element) -> Vec<Element> {
-        if let Some(sequence) = element.as_sequence() {
-            self.match_sequence_against_key(sequence)
-        }
-        else if let Some(st) = element.as_struct() {
-            self.match_struct_against_key(st)
-        }
-        else {
-            Vec::new()
-        }
+        self.match_key_ref(element).into_iter().cloned().collect()
     }
 
-    fn match_sequence_against_key(&self, sequence: &Sequence) -> Vec<Element> {
-        if sequence.is_empty() { return Vec::new(); }
-        match &self.key {
-            Key::Index(i) => {
-                if let Some(mut small) = i.to_i32() {
-                    while small < 0 {
-                        small += sequence.len() as i32;
-                    }
-                    if let Some(e) = sequence.get(small as usize) {
-                        return vec![e.clone()];
-                    }
-                }
-            },
-            Key::Slice(start, end, step) => {
-                let mut start = start.unwrap_or(0);
-                let mut end = end.unwrap_or(sequence.len() as i32 - 1);
-                let step = step.unwrap_or(1);
-                // sequence.len() is known to be > 0 at this point
-                while start < 0 { start += sequence.len() as i32; }
-                while end < 0 { end += sequence.len() as i32; }
-                if start == end {
-                    return match sequence.get(start as usize) {
-                        Some(e) => vec![e.clone()],
-                        None => vec![]
-                    }
-                }
-                let mut results = Vec::new();
-                if step > 0 {
-                    if end > start {
-                        let mut i = start;
-                        while i <= end {
-                            if let Some(elem) = sequence.get(i as usize) {
-                                results.push(elem.clone());
-                            }
-                            i += step;
-                        }
-                    }
-                    // else step positive & end < start -> set of indices is empty,
-                    // fall through and return empty set
-                }
-                else if step < 0 {
-                    if start > end {
-                        let mut i = start;
-                        while i >= end {
-                            if let Some(elem) = sequence.get(i as usize) {
-                                results.push(elem.clone());
-                            }
-                            i += /* negative */step;
-                        }
-                    }
-                    // else step negative & start < end -> set of indices is empty,
-                    // fall through and return empty set
-                }
-                // step == 0 -> empty set; return results without adding any elements
-                return results;
-            },
-            Key::String(s) | Key::Symbol(s) => {
-                if s.as_str() == "*" {
-                    return sequence.elements().map(|r| r.clone()).collect();
-                }
-                // non-wildcard string keys never match sequence elements
-            }
-        }
-        Vec::new()
+    /// like [`Segment::match_key`], but returns references into `element` instead of clones.
+    pub fn match_key_ref<'a>(&self, element: &'a Element) -> Vec<&'a Element> {
+        KeyMatcher::new(&self.key).matched_children(&Rc::new(Node::root(element, 0)), self.name_matching).into_iter()
+            .map(|node| node.element)
+            .collect()
     }
 
-    fn match_struct_against_key(&self, st: &Struct) -> Vec<Element> {
-        match &self.key {
-            Key::String(s) | Key::Symbol(s) =>  {
-                let mut results = Vec::new();
-                for (key, val) in st.fields() {
-                    if wildmatch::WildMatch::new(&s).matches(key.text().unwrap_or("$0")) {
-                        results.push(val.clone());
-                    }
-                }
-                results
-            },
-            _ => Vec::new()
-        }
+    /// input: a single element of any type. may be a sequence, struct, or value.
+    /// output: results of matching every descendant of that element against this segment's key,
+    ///         i.e. the behavior of a recursive (`//key`) segment.
+    ///
+    /// Results are always returned in document order (pre-order, depth-first): a matching
+    /// element comes before any matches nested inside of it, and siblings keep the order they
+    /// have in their parent sequence or struct. This also applies to slices with a negative
+    /// step, which select items in reverse order when matched non-recursively.
+    /// Overlapping matches are not collapsed: if a matching element contains further matches,
+    /// the outer element and every nested match are all part of the results.
+    pub fn match_key_recursive(&self, element: &Element) -> Vec<Element> {
+        self.match_key_recursive_ref(element).into_iter().cloned().collect()
+    }
+
+    /// like [`Segment::match_key_recursive`], but returns references into `element` instead
+    /// of clones.
+    pub fn match_key_recursive_ref<'a>(&self, element: &'a Element) -> Vec<&'a Element> {
+        let key = KeyMatcher::new(&self.key);
+        let ctx = EvalContext::new(std::slice::from_ref(element));
+        let mut matches = KeyMatches::new(&key, Axis::Child, true, self.name_matching, vec![Node::root(element, 0)]);
+        // without any limits, matching never fails
+        std::iter::from_fn(|| matches.next(&key, &ctx).ok().flatten()).map(|node| node.element).collect()
     }
 }
@@ -0,0 +1,94 @@
+use ion_rs::IonResult;
+use ion_rs::element::Element;
+use crate::{CompiledPath, EvalContext, EvalError, EvalOptions, Path, StreamError};
+use crate::location::Node;
+
+
+/// A single result of evaluating a path against a stream of top-level values.
+#[derive(Debug, Clone, PartialEq)]
+pub struct StreamMatch {
+    /// zero-based index of the top-level value this match was found in.
+    pub index: usize,
+    pub element: Element,
+}
+
+impl CompiledPath {
+    /// evaluates this path against every top-level value in `stream`, in order.
+    ///
+    /// each top-level value is used as the starting point of the path in turn, so a leading
+    /// `/key` matches the field `key` of every top-level struct. absolute paths inside
+    /// predicates are evaluated against the whole stream.
+    pub fn match_stream(&self, stream: &[Element]) -> Vec<StreamMatch> {
+        // evaluating with the default options never fails
+        self.try_match_stream(stream, EvalOptions::default()).unwrap_or_default()
+    }
+
+    /// like [`CompiledPath::match_stream`], evaluating with the given options. the limits
+    /// apply to the whole stream, not to each top-level value separately.
+    pub fn try_match_stream(&self, stream: &[Element], options: EvalOptions) -> Result<Vec<StreamMatch>, EvalError> {
+        let ctx = EvalContext::new(stream).with_options(options);
+        let mut results = Vec::new();
+        for (index, value) in stream.iter().enumerate() {
+            results.extend(self.evaluate(&ctx, vec![Node::root(value, index)])?.into_iter()
+                .map(|node| StreamMatch { index, element: node.element.clone() }));
+        }
+        Ok(results)
+    }
+
+    /// like [`CompiledPath::match_stream`], for top-level values coming from an iterator.
+    ///
+    /// since predicates may refer back to any top-level value, the iterator is consumed
+    /// entirely before evaluation starts.
+    pub fn match_iter<I: IntoIterator<Item = Element>>(&self, stream: I) -> Vec<StreamMatch> {
+        let stream: Vec<Element> = stream.into_iter().collect();
+        self.match_stream(&stream)
+    }
+
+    /// like [`CompiledPath::match_iter`], evaluating with the given options.
+    pub fn try_match_iter<I: IntoIterator<Item = Element>>(&self, stream: I, options: EvalOptions) -> Result<Vec<StreamMatch>, EvalError> {
+        let stream: Vec<Element> = stream.into_iter().collect();
+        self.try_match_stream(&stream, options)
+    }
+
+    /// like [`CompiledPath::match_stream`], for an Ion stream in text or binary encoding.
+    pub fn match_bytes<A: AsRef<[u8]>>(&self, data: A) -> IonResult<Vec<StreamMatch>> {
+        Ok(self.match_stream(&Element::read_all(data)?))
+    }
+
+    /// like [`CompiledPath::match_bytes`], evaluating with the given options.
+    pub fn try_match_bytes<A: AsRef<[u8]>>(&self, data: A, options: EvalOptions) -> Result<Vec<StreamMatch>, StreamError> {
+        Ok(self.try_match_stream(&Element::read_all(data)?, options)?)
+    }
+}
+
+impl Path {
+    /// see [`CompiledPath::match_stream`].
+    pub fn match_stream(&self, stream: &[Element]) -> Vec<StreamMatch> {
+        self.compile().match_stream(stream)
+    }
+
+    /// see [`CompiledPath::match_iter`].
+    pub fn match_iter<I: IntoIterator<Item = Element>>(&self, stream: I) -> Vec<StreamMatch> {
+        self.compile().match_iter(stream)
+    }
+
+    /// see [`CompiledPath::match_bytes`].
+    pub fn match_bytes<A: AsRef<[u8]>>(&self, data: A) -> IonResult<Vec<StreamMatch>> {
+        self.compile().match_bytes(data)
+    }
+
+    /// see [`CompiledPath::try_match_stream`].
+    pub fn try_match_stream(&self, stream: &[Element], options: EvalOptions) -> Result<Vec<StreamMatch>, EvalError> {
+        self.compile().try_match_stream(stream, options)
+    }
+
+    /// see [`CompiledPath::try_match_iter`].
+    pub fn try_match_iter<I: IntoIterator<Item = Element>>(&self, stream: I, options: EvalOptions) -> Result<Vec<StreamMatch>, EvalError> {
+        self.compile().try_match_iter(stream, options)
+    }
+
+    /// see [`CompiledPath::try_match_bytes`].
+    pub fn try_match_bytes<A: AsRef<[u8]>>(&self, data: A, options: EvalOptions) -> Result<Vec<StreamMatch>, StreamError> {
+        self.compile().try_match_bytes(data, options)
+    }
+}
@@ -328,6 +328,7 @@ macro_rules! assert_timestamp_eq {
 }
 
 #[test]
+#[allow(clippy::zero_prefixed_literal)]
 fn test_timestamps_positive() {
     assert_timestamp_eq!("0001T"            = Timestamp::with_year(1).build().unwrap());
     assert_timestamp_eq!("0001-01T"          = Timestamp::with_year(1).with_month(1).build().unwrap());
@@ -1,8 +1,18 @@
 use num::{BigInt, Num};
-use crate::parser::ionpath_parser;
-use crate::{Path, Segment, Key};
+use ion_rs::IonType;
+use crate::parser::{self, ionpath_parser};
+use crate::{AnnotationKey, ArithOp, Axis, CompareOp, Expr, Function, Literal, NameMatching, Path, PathUnion, Predicate, PredicateExpr, Segment, Key, ParseError, ParseOptions};
 
 
+/// the first predicate in `expr`, e.g. `a` in `[not(a) or b]`.
+fn first_predicate(expr: &PredicateExpr) -> &Predicate {
+    match expr {
+        PredicateExpr::Predicate(pred) => pred,
+        PredicateExpr::And(exprs) | PredicateExpr::Or(exprs) => first_predicate(&exprs[0]),
+        PredicateExpr::Not(expr) => first_predicate(expr),
+    }
+}
+
 #[test]
 fn test_parsing_keys() {
     // symbol keys
@@ -210,4 +220,305 @@ fn test_parsing_comparison_predicates() {
 #[test]
 fn test_parsing_all() {
     // TODO
-}
\ No newline at end of file
+}
+
+#[test]
+fn test_parse_limits() {
+    let limited = |q: &str, options: ParseOptions| parser::parse(q, options).err();
+
+    assert_eq!(parser::parse("/a/b", ParseOptions::default()), ionpath_parser::path("/a/b").map_err(ParseError::Syntax));
+    assert!(matches!(limited("/a/b[", ParseOptions::default()), Some(ParseError::Syntax(_))));
+
+    assert_eq!(limited("/a/b", ParseOptions::new().with_max_length(4)), None);
+    assert_eq!(limited("/a/bc", ParseOptions::new().with_max_length(4)), Some(ParseError::TooLong { length: 5, max: 4 }));
+
+    let depth = ParseOptions::new().with_max_predicate_depth(2);
+    assert_eq!(limited("/a[b[c]][d[e] or f[g]]", depth), None);
+    assert_eq!(limited("/a[b[c[d]]]", depth), Some(ParseError::TooDeep { offset: 6, max: 2 }));
+    // brackets inside literals don't count
+    assert_eq!(limited("/a[b = \"[[[\"]['[[[' = 1]", depth), None);
+    // so do parentheses inside of predicates
+    assert_eq!(limited("/a[not((b) and c)]", depth), None);
+    assert_eq!(limited("/a[not((b or (c)))]", depth), Some(ParseError::TooDeep { offset: 13, max: 2 }));
+    assert_eq!(limited(&format!("/a[{}b]", "not(".repeat(100_000)), depth), Some(ParseError::TooDeep { offset: 14, max: 2 }));
+    // rejected before parsing, even though it isn't a valid query anyway
+    let hostile = "[".repeat(100_000);
+    assert_eq!(limited(&hostile, depth), Some(ParseError::TooDeep { offset: 2, max: 2 }));
+
+    let segments = ParseOptions::new().with_max_segments(4);
+    assert_eq!(limited("/a//b/c/d", segments), None);
+    assert_eq!(limited("a/b[c/d]", segments), None);
+    assert_eq!(limited("/a[b or /c = 1 OR (x|y)::d]", segments), None);
+    assert_eq!(limited("/a[b or /c = 1 OR (x|y)::d/e]", segments), Some(ParseError::TooManySegments { offset: 26, max: 4 }));
+    assert_eq!(limited("/a[not(b) AND (c or d)]", segments), None);
+    assert_eq!(limited("/a[not(b) and (c or d/e)]", segments), Some(ParseError::TooManySegments { offset: 21, max: 4 }));
+    assert_eq!(limited("/a/'x/y'/\"[/]\"/-1:2/e", segments), Some(ParseError::TooManySegments { offset: 19, max: 4 }));
+    assert_eq!(limited("/a[b = c][> 1]/d[e = f]/g", segments), Some(ParseError::TooManySegments { offset: 23, max: 4 }));
+    assert_eq!(limited(&"/a".repeat(5), segments), Some(ParseError::TooManySegments { offset: 8, max: 4 }));
+
+    let literals = ParseOptions::new().with_max_literal_size(5);
+    assert_eq!(limited("/abcde['x' = \"y\"][n = 12345]", literals), None);
+    assert_eq!(limited("/abcdef", literals), Some(ParseError::LiteralTooLarge { offset: 1, length: 6, max: 5 }));
+    assert_eq!(limited("/a[b = \"abcd\"]", literals), Some(ParseError::LiteralTooLarge { offset: 7, length: 6, max: 5 }));
+    assert_eq!(limited("/a[b = 123456]", literals), Some(ParseError::LiteralTooLarge { offset: 7, length: 6, max: 5 }));
+    assert_eq!(limited("/a[b = {{ aGVsbG8= }}]", literals), Some(ParseError::LiteralTooLarge { offset: 7, length: 14, max: 5 }));
+    assert_eq!(limited("/a[b = '''ab\\''''']", literals), Some(ParseError::LiteralTooLarge { offset: 7, length: 10, max: 5 }));
+}
+
+
+#[test]
+fn test_parsing_parent_steps() {
+    assert_eq!(ionpath_parser::path("/a/.."), Ok(Path {
+        absolute: true,
+        segments: vec![Segment::new(false, Key::Symbol("a".into())), Segment::parent()].into()
+    }));
+    assert_eq!(ionpath_parser::path("../b"), Ok(Path {
+        absolute: false,
+        segments: vec![Segment::parent(), Segment::new(false, Key::Symbol("b".into()))].into()
+    }));
+    assert_eq!(ionpath_parser::path("a/ ..[b]/..").unwrap().segments[1], Segment::parent()
+        .with_predicate(Predicate::Path(Box::new(ionpath_parser::path("b").unwrap())).into()));
+    assert_eq!(ionpath_parser::path("/a[../b]").unwrap().segments[0].predicates[0],
+        PredicateExpr::Predicate(Predicate::Path(Box::new(Path {
+            absolute: false,
+            segments: vec![Segment::parent(), Segment::new(false, Key::Symbol("b".into()))].into()
+        }))));
+    assert_eq!(Segment::parent().axis, Axis::Parent);
+    // `..` never searches recursively, and only matches as a whole
+    assert!(ionpath_parser::path("/a//..").is_err());
+    assert!(ionpath_parser::path("/a/...").is_err());
+    assert!(ionpath_parser::path("/a/x::..").is_err());
+}
+
+#[test]
+fn test_parsing_axes() {
+    let path = ionpath_parser::path("/a/ancestor::b/following-sibling::*/preceding-sibling::x::0").unwrap();
+    let axes: Vec<Axis> = path.segments.iter().map(|seg| seg.axis).collect();
+    assert_eq!(axes, vec![Axis::Child, Axis::Ancestor, Axis::FollowingSibling, Axis::PrecedingSibling]);
+    assert_eq!(path.segments[3], Segment::new(false, Key::Index(0.into()))
+        .with_axis(Axis::PrecedingSibling)
+        .with_annotation_list(vec!["x".into()]));
+    assert_eq!(ionpath_parser::path("following-sibling::*[a]").unwrap().segments[0].axis, Axis::FollowingSibling);
+
+    // `descendant::` is the same as `//`
+    assert_eq!(ionpath_parser::path("/descendant::a/descendant::b"), ionpath_parser::path("//a//b"));
+    assert_eq!(ionpath_parser::path("descendant::a").unwrap().segments[0], Segment::new(true, Key::Symbol("a".into())));
+
+    // quoting an axis name makes it an annotation
+    let seg = &ionpath_parser::path("/'ancestor'::a").unwrap().segments[0];
+    assert_eq!((seg.axis, &seg.annotation_lists), (Axis::Child, &vec![vec![AnnotationKey::from("ancestor")]]));
+    // without `::` it's a key
+    assert_eq!(ionpath_parser::path("/ancestor").unwrap().segments[0], Segment::new(false, Key::Symbol("ancestor".into())));
+    // axes other than child never search recursively
+    assert!(ionpath_parser::path("/a//ancestor::b").is_err());
+    assert!(ionpath_parser::path("/a/descendant::following-sibling::b").is_err());
+}
+
+#[test]
+fn test_parsing_unions() {
+    let path = |q: &str| ionpath_parser::path(q).unwrap();
+    assert_eq!(ionpath_parser::union("/a/b | /c/d[e>1]"), Ok(PathUnion::new(vec![path("/a/b"), path("/c/d[e>1]")])));
+    assert_eq!(ionpath_parser::union(" a|b | //c "), Ok(PathUnion::new(vec![path("a"), path("b"), path("//c")])));
+    assert_eq!(ionpath_parser::union("/a"), Ok(PathUnion::from(path("/a"))));
+    // `|` in annotation choice lists still belongs to the segment
+    assert_eq!(ionpath_parser::union("(x|y)::a | b").unwrap().paths(), [path("(x|y)::a"), path("b")]);
+    // only at the top level
+    assert!(ionpath_parser::path("/a | /b").is_err());
+    assert!(ionpath_parser::union("/a[b | c]").is_err());
+    assert!(ionpath_parser::union("/a |").is_err());
+
+    let segments = ParseOptions::new().with_max_segments(4);
+    assert!(parser::parse_union("a/b | c | (x|y)::d", segments).is_ok());
+    assert_eq!(parser::parse_union("a/b | c | d/e", segments).err(), Some(ParseError::TooManySegments { offset: 11, max: 4 }));
+}
+
+#[test]
+fn test_parsing_key_choices() {
+    let key = |q: &str| ionpath_parser::path(q).map(|path| path.segments[0].key.clone());
+    assert_eq!(key("/(name|title)"), Ok(Key::Choice(vec![Key::Symbol("name".into()), Key::Symbol("title".into())])));
+    assert_eq!(key("( 0 | -1 | 'a*' | \"b\" | 1:3 )"), Ok(Key::Choice(vec![
+        Key::Index(0.into()),
+        Key::Index((-1).into()),
+        Key::Symbol("a*".into()),
+        Key::String("b".into()),
+        Key::Slice(Some(1), Some(3), None),
+    ])));
+    // a single choice is just the key
+    assert_eq!(key("/(name)"), key("/name"));
+    // followed by `::` it's still an annotation choice list
+    let seg = &ionpath_parser::path("/(a|b)::(c|d)[e]").unwrap().segments[0];
+    assert_eq!(seg.annotation_lists, vec![vec!["a".into(), "b".into()]]);
+    assert_eq!(seg.key, Key::Choice(vec![Key::Symbol("c".into()), Key::Symbol("d".into())]));
+    assert!(ionpath_parser::path("/()").is_err());
+    assert!(ionpath_parser::path("/(a|)").is_err());
+    assert!(ionpath_parser::path("/(a|(b|c))").is_err());
+}
+
+#[test]
+fn test_parsing_regexes() {
+    let seg = |q: &str| ionpath_parser::path(q).map(|path| path.segments[0].clone());
+    assert_eq!(seg(r#"/~"^attr_[0-9]+$""#), Ok(Segment::new(false, Key::Regex("^attr_[0-9]+$".into()))));
+    assert_eq!(seg(r#"//~ "a|b""#), Ok(Segment::new(true, Key::Regex("a|b".into()))));
+    // ion escapes are applied first
+    assert_eq!(seg(r#"/~"\\d""#).unwrap().key, Key::Regex("\\d".into()));
+    assert_eq!(seg(r#"/(~"^a"|b)"#).unwrap().key, Key::Choice(vec![Key::Regex("^a".into()), Key::Symbol("b".into())]));
+    assert_eq!(seg(r#"/~"^v[0-9]+$"::(x|~"y")::*"#), Ok(Segment::new(false, Key::Symbol("*".into()))
+        .with_annotation_list(vec![AnnotationKey::Regex("^v[0-9]+$".into())])
+        .with_annotation_list(vec!["x".into(), AnnotationKey::Regex("y".into())])));
+    assert!(seg(r#"/~"(""#).is_err());
+    assert!(seg(r#"/~"[a"::*"#).is_err());
+    assert!(seg("/~a").is_err());
+}
+
+#[test]
+fn test_parsing_name_matching() {
+    let seg = |q: &str| ionpath_parser::path(q).map(|path| path.segments[0].clone());
+    assert_eq!(seg("/userid~i"), Ok(Segment::new(false, Key::Symbol("userid".into())).with_name_matching(NameMatching::new().ignore_case())));
+    assert_eq!(seg("/a::name~n[b]").unwrap().name_matching, NameMatching::new().normalize());
+    assert_eq!(seg(r#"//(a|"b")~ni"#).unwrap().name_matching, NameMatching::new().ignore_case().normalize());
+    assert_eq!(seg(r#"/~"^a"~i"#).unwrap().key, Key::Regex("^a".into()));
+    assert!(seg("/a").unwrap().name_matching.is_exact());
+    assert!(seg("/a~").is_err());
+    assert!(seg("/a~x").is_err());
+    assert!(seg("/a~ii").is_err());
+    assert!(seg("/a ~i").is_err());
+}
+
+#[test]
+fn test_parsing_expressions() {
+    let pred = |q: &str| ionpath_parser::path(q).map(|path| first_predicate(&path.segments[0].predicates[0]).clone());
+    let int = |i: i32| Expr::Literal(Literal::Integer(BigInt::from(i)));
+    let call = |f: Function| Expr::Call(f, Vec::new());
+
+    assert_eq!(pred("*[position() > last() - 3]"), Ok(Predicate::Condition {
+        lhs: call(Function::Position),
+        op: CompareOp::GreaterThan,
+        rhs: Expr::Arithmetic(Box::new(call(Function::Last)), ArithOp::Subtract, Box::new(int(3))),
+    }));
+    // left-associative, and parentheses group
+    assert_eq!(pred("*[1 - 2 + 3 = 2]").unwrap(), pred("*[(1 - 2) + 3 = 2]").unwrap());
+    assert_ne!(pred("*[1 - 2 + 3 = 2]").unwrap(), pred("*[1 - (2 + 3) = 2]").unwrap());
+    // paths as operands
+    assert_eq!(pred("*[a > b + 1]"), Ok(Predicate::Condition {
+        lhs: Expr::Path(Box::new(ionpath_parser::path("a").unwrap())),
+        op: CompareOp::GreaterThan,
+        rhs: Expr::Arithmetic(Box::new(Expr::Path(Box::new(ionpath_parser::path("b").unwrap()))), ArithOp::Add, Box::new(int(1))),
+    }));
+    // plain comparisons are unchanged, including symbol literals
+    assert!(matches!(pred("*[a = foo]"), Ok(Predicate::Compare { .. })));
+    assert!(matches!(pred("*[a > 3 or b]"), Ok(Predicate::Compare { .. })));
+    assert!(matches!(pred("*[position() = 0]"), Ok(Predicate::Condition { .. })));
+
+    assert!(pred("*[nope() = 0]").is_err());
+    assert!(pred("*[position(1) = 0]").is_err());
+    assert!(pred("*[position() = ]").is_err());
+
+    // each alternative of a predicate reuses the path or expression the others parsed, so
+    // deeply nested predicates don't take exponentially long
+    for (open, close) in [("[b", "]"), ("[count(b", ") > 1]"), ("[b + 1 = c", "]"), ("[not(b", ")]"), ("[(b", " or c)]"), ("[lower(b", ") = 'x']")] {
+        let query = format!("/a{}{}", open.repeat(20), close.repeat(20));
+        assert!(ionpath_parser::path(&query).is_ok(), "{}", query);
+    }
+}
+
+#[test]
+fn test_parsing_functions() {
+    let pred = |q: &str| ionpath_parser::path(q).map(|path| first_predicate(&path.segments[0].predicates[0]).clone());
+    let path = |q: &str| Expr::Path(Box::new(ionpath_parser::path(q).unwrap()));
+    let string = |s: &str| Expr::Literal(Literal::String(s.into()));
+
+    assert_eq!(pred(r#"*[starts_with(name, "x")]"#), Ok(Predicate::Call(Function::StartsWith, vec![path("name"), string("x")])));
+    assert_eq!(pred("*[exists(a/b[c])]"), Ok(Predicate::Call(Function::Exists, vec![path("a/b[c]")])));
+    assert_eq!(pred("*[count( * ) > 2]"), Ok(Predicate::Condition {
+        lhs: Expr::Call(Function::Count, vec![path("*")]),
+        op: CompareOp::GreaterThan,
+        rhs: Expr::Literal(Literal::Integer(BigInt::from(2))),
+    }));
+    // single values default to the element being filtered
+    assert!(matches!(pred("*[length() = 3]"), Ok(Predicate::Condition { lhs: Expr::Call(Function::Length, args), .. }) if args.is_empty()));
+    assert!(pred("*[floor(abs(n) + 0.5) = round(lower(s))]").is_ok());
+    assert!(pred(r#"*[a or contains(b, "c")]"#).is_ok());
+    // a quoted symbol ending the predicate is a literal, as in plain comparisons
+    assert_eq!(pred("*[lower(s) = 'active']"), Ok(Predicate::Condition {
+        lhs: Expr::Call(Function::Lower, vec![path("s")]),
+        op: CompareOp::Equal,
+        rhs: Expr::Literal(Literal::Symbol("active".into())),
+    }));
+    assert!(matches!(pred("*[lower(s) = 'a b' and c]"), Ok(Predicate::Condition { rhs: Expr::Literal(Literal::Symbol(_)), .. })));
+    assert!(matches!(pred("*[lower(s) = 'a b' + 1]"), Ok(Predicate::Condition { rhs: Expr::Arithmetic(..), .. })));
+    assert!(matches!(pred("*[lower(s) = active]"), Ok(Predicate::Condition { rhs: Expr::Path(_), .. })));
+
+    assert!(pred("*[count() = 0]").is_err());
+    assert!(pred("*[contains(a)]").is_err());
+    assert!(pred("*[lower(a, b) = c]").is_err());
+    // only functions returning booleans are predicates on their own
+    assert!(pred("*[length(a)]").is_err());
+}
+
+#[test]
+fn test_parsing_types() {
+    let seg = |q: &str| ionpath_parser::path(q).map(|path| path.segments[0].clone());
+    assert_eq!(seg("/*:int"), Ok(Segment::new(false, Key::Symbol("*".into())).with_types(vec![IonType::Int])));
+    assert_eq!(seg("/a:( struct | list )[b]").unwrap().types, vec![IonType::Struct, IonType::List]);
+    assert_eq!(seg("//x::a~i:sexp").unwrap().types, vec![IonType::SExp]);
+    assert_eq!(seg("/0:null").unwrap().key, Key::Index(BigInt::from(0)));
+    assert_eq!(seg("/0:").unwrap().key, Key::Slice(Some(0), None, None));
+    assert_eq!(seg("/a::b").unwrap().types, vec![]);
+    assert!(seg("/a:integer").is_err());
+    assert!(seg("/a:(int|)").is_err());
+    assert!(seg("/a:int~i").is_err());
+
+    let pred = |q: &str| ionpath_parser::path(q).map(|path| first_predicate(&path.segments[0].predicates[0]).clone());
+    assert_eq!(pred("*[type() = struct]"), Ok(Predicate::Condition {
+        lhs: Expr::Call(Function::Type, Vec::new()),
+        op: CompareOp::Equal,
+        rhs: Expr::Literal(Literal::Symbol("struct".into())),
+    }));
+    assert!(matches!(pred("*[type(a) != null or b]"), Ok(Predicate::Condition { rhs: Expr::Literal(Literal::Symbol(_)), .. })));
+    assert_eq!(pred("*[is_null(a)]"), Ok(Predicate::Call(Function::IsNull, vec![Expr::Path(Box::new(ionpath_parser::path("a").unwrap()))])));
+    // only `=` and `!=` compare with bare type names, otherwise they're paths as usual
+    assert!(matches!(pred("*[type() < int]"), Ok(Predicate::Condition { rhs: Expr::Path(_), .. })));
+}
+
+#[test]
+fn test_parsing_boolean_predicates() {
+    let bracket = |q: &str| ionpath_parser::path(q).map(|path| path.segments[0].predicates[0].clone());
+    let path = |q: &str| PredicateExpr::Predicate(Predicate::Path(Box::new(ionpath_parser::path(q).unwrap())));
+    let cmp = |q: &str, value: i32| PredicateExpr::Predicate(Predicate::Compare {
+        path: Some(Box::new(ionpath_parser::path(q).unwrap())),
+        op: CompareOp::Equal,
+        value: Literal::Integer(BigInt::from(value)),
+    });
+
+    // existing syntax
+    assert_eq!(bracket("*[a]"), Ok(path("a")));
+    assert_eq!(bracket("*[a or b OR c]"), Ok(PredicateExpr::Or(vec![path("a"), path("b"), path("c")])));
+    assert_eq!(ionpath_parser::path("*[a][b]").unwrap().segments[0].predicates, vec![path("a"), path("b")]);
+
+    // `and` binds more tightly than `or`, parentheses group
+    assert_eq!(bracket("*[a or b and c]"), Ok(PredicateExpr::Or(vec![path("a"), PredicateExpr::And(vec![path("b"), path("c")])])));
+    assert_eq!(bracket("*[(a = 1 or b = 2) and c = 3]"), Ok(PredicateExpr::And(vec![
+        PredicateExpr::Or(vec![cmp("a", 1), cmp("b", 2)]),
+        cmp("c", 3),
+    ])));
+    assert_eq!(bracket("*[((a))]"), Ok(path("a")));
+    // `not` binds most tightly
+    assert_eq!(bracket("*[not(a = 1)]"), Ok(PredicateExpr::Not(Box::new(cmp("a", 1)))));
+    assert_eq!(bracket("*[NOT (a) And b]"), Ok(PredicateExpr::And(vec![PredicateExpr::Not(Box::new(path("a"))), path("b")])));
+    assert_eq!(bracket("*[not(a or b)]"), Ok(PredicateExpr::Not(Box::new(PredicateExpr::Or(vec![path("a"), path("b")])))));
+
+    // parenthesized keys are still paths
+    assert_eq!(bracket("*[(a|b) = 1]"), Ok(cmp("(a|b)", 1)));
+    assert_eq!(bracket("*[(a) = 1 and b]"), Ok(PredicateExpr::And(vec![cmp("a", 1), path("b")])));
+    // and so are fields called like the operators
+    assert_eq!(bracket("*[not]"), Ok(path("not")));
+    assert_eq!(bracket("*[and = 1 or order]"), Ok(PredicateExpr::Or(vec![cmp("and", 1), path("order")])));
+    // symbol literals end before an operator
+    assert!(matches!(bracket("*[a = b and c]"), Ok(PredicateExpr::And(_))));
+
+    assert!(bracket("*[a and]").is_err());
+    assert!(bracket("*[or a]").is_err());
+    assert!(bracket("*[not a]").is_err());
+    assert!(bracket("*[(a or b]").is_err());
+    assert!(bracket("*[not()]").is_err());
+}
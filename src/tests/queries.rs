@@ -1 +1,1079 @@
-// TODO
\ No newline at end of file
+use ion_rs::element::Element;
+use crate::parser::ionpath_parser;
+use crate::{explain, AnnotationKey, EvalContext, EvalError, EvalOptions, Function, Key, Limit, Location, NameMatching, Path, Predicate, PredicateExpr, QuerySet, Segment, SegmentEvent, Stage, Step, StreamError, StreamMatch};
+
+
+fn query(path: &str, doc: &str) -> Vec<Element> {
+    let path = ionpath_parser::path(path).unwrap();
+    path.match_element(Element::read_one(doc).unwrap())
+}
+
+fn elements(values: &str) -> Vec<Element> {
+    Element::read_all(values).unwrap()
+}
+
+/// checks that reading `values` from a reader finds the same matches of `path` as evaluating
+/// the parsed stream, and that a query set and lazy iteration agree with [`Path::match_ref`]
+/// on each of the values.
+fn assert_engines_agree(path: &str, values: &str) {
+    let parsed = ionpath_parser::path(path).unwrap();
+    let mut reader = ion_rs::ReaderBuilder::new().build(values).unwrap();
+    assert_eq!(parsed.match_reader(&mut reader).unwrap(), parsed.match_stream(&elements(values)), "{}", path);
+    let set: QuerySet = [parsed.clone()].into_iter().collect();
+    for root in elements(values) {
+        let found: Vec<&Element> = set.match_ref(&root).into_iter().map(|m| m.element).collect();
+        assert_eq!(found, parsed.match_ref(&root), "{}", path);
+        assert_eq!(parsed.iter(&root).collect::<Vec<_>>(), parsed.match_ref(&root), "{}", path);
+    }
+}
+
+
+#[test]
+fn test_recursive_descent() {
+    let doc = r#"{ a: 1, b: { a: 2, c: [ { a: 3 }, 4, { d: { a: 5 } } ] }, a: 6 }"#;
+
+    // matches at every depth, in document order
+    assert_eq!(query("//a", doc), elements("1 2 3 5 6"));
+    assert_eq!(query("/b//a", doc), elements("2 3 5"));
+    assert_eq!(query("/b/c//a", doc), elements("3 5"));
+    assert_eq!(query("//d/a", doc), elements("5"));
+    assert_eq!(query("//x", doc), elements(""));
+
+    // descends into lists and sexps
+    let doc = r#"[ (x::1 (x::2)), { k: x::[ x::3 ] } ]"#;
+    assert_eq!(query("//x::*", doc), elements("x::1 x::2 x::[x::3] x::3"));
+    assert_eq!(query("//0", doc), elements("(x::1 (x::2)) x::1 x::2 x::3"));
+    assert_eq!(query("//-1", doc), elements("(x::2) x::2 { k: x::[ x::3 ] } x::3"));
+
+    // nested matches are all returned, outer before inner
+    let doc = r#"{ n: { n: { n: 1 } } }"#;
+    assert_eq!(query("//n", doc), elements("{ n: { n: 1 } } { n: 1 } 1"));
+
+    // an element below several matches of an earlier recursive segment is returned once
+    let doc = r#"{ n: { n: { x: 1 } }, m: [ { n: { x: 2 } } ] }"#;
+    assert_eq!(query("//n//x", doc), elements("1 2"));
+    assert_eq!(query("//*//x", doc), elements("1 2"));
+    assert_eq!(query("//n/n//x", doc), elements("1"));
+    assert_eq!(query("/n/..//n//*", doc), elements("{ x: 1 } 1 2"));
+    for q in ["//n//x", "//*//x", "//n/n//x", "/n/..//n//*", "//*//*//*"] {
+        assert_engines_agree(q, doc);
+    }
+
+    // document order is kept even for reversed slices
+    let doc = r#"{ s: [1, 2, 3] }"#;
+    assert_eq!(query("/s/2:0:-1", doc), elements("3 2 1"));
+    assert_eq!(query("//2:0:-1", doc), elements("1 2 3"));
+
+    // predicates apply to every recursive match
+    let doc = r#"{ a: { v: 1 }, b: [ { a: { v: 2 } }, { a: { v: 3 } } ] }"#;
+    assert_eq!(query("//a[v > 1]", doc), elements("{ v: 2 } { v: 3 }"));
+    assert_eq!(query("//a[v > 1]/v", doc), elements("2 3"));
+}
+
+
+#[test]
+fn test_absolute_subpaths_in_predicates() {
+    let doc = r#"{
+        header: B::{ valid: true },
+        items: [ A::{ id: 1, v: 2 }, A::{ id: 2, v: 1 } ],
+        limit: 1
+    }"#;
+
+    // absolute predicate paths start at the document root, not the candidate element
+    assert_eq!(query("//A::*[/B::*[valid = true]]/id", doc), elements("1 2"));
+    assert_eq!(query("//A::*[/B::*[valid = false]]/id", doc), elements(""));
+    assert_eq!(query("//A::*[/header/valid]/id", doc), elements("1 2"));
+    // relative predicate paths still start at the candidate element
+    assert_eq!(query("//A::*[B::*]/id", doc), elements(""));
+    assert_eq!(query("//A::*[v > 1]/id", doc), elements("1"));
+    // absolute paths nested several predicates deep still see the root
+    assert_eq!(query("/items/*[id[/limit = 1]][v = 1]/id", doc), elements("2"));
+    assert_eq!(query("/items/*[id[/limit = 2]]/id", doc), elements(""));
+}
+
+
+#[test]
+fn test_stream_queries() {
+    let stream = r#"
+        { id: 1, tags: [a, b] }
+        header::{ version: 2 }
+        { id: 3, tags: [] }
+        5
+        { id: 4, tags: [c] }
+    "#;
+    let path = ionpath_parser::path("/id").unwrap();
+    let expected = vec![
+        StreamMatch { index: 0, element: Element::from(1) },
+        StreamMatch { index: 2, element: Element::from(3) },
+        StreamMatch { index: 4, element: Element::from(4) },
+    ];
+    assert_eq!(path.match_stream(&elements(stream)), expected);
+    assert_eq!(path.match_iter(elements(stream)), expected);
+    assert_eq!(path.match_bytes(stream).unwrap(), expected);
+    let binary: Vec<u8> = elements(stream).iter().flat_map(|e| e.to_binary().unwrap()).collect();
+    assert_eq!(path.match_bytes(binary).unwrap(), expected);
+
+    // relative and recursive paths also start at each top-level value
+    let path = ionpath_parser::path("tags/*").unwrap();
+    assert_eq!(path.match_stream(&elements(stream)).into_iter().map(|m| m.index).collect::<Vec<_>>(), vec![0, 0, 4]);
+    let path = ionpath_parser::path("//version").unwrap();
+    assert_eq!(path.match_stream(&elements(stream)), vec![StreamMatch { index: 1, element: Element::from(2) }]);
+
+    // absolute predicate paths see every top-level value
+    let path = ionpath_parser::path("/id[/version = 2]").unwrap();
+    assert_eq!(path.match_stream(&elements(stream)), expected);
+    let path = ionpath_parser::path("/id[/version = 3]").unwrap();
+    assert_eq!(path.match_stream(&elements(stream)), vec![]);
+
+    assert!(path.match_bytes("{ unterminated: ").is_err());
+}
+
+
+#[test]
+fn test_borrowed_matches() {
+    let doc = Element::read_one(r#"{ a: [ { b: 1 }, { b: 2 }, { c: 3 } ] }"#).unwrap();
+    let items = doc.as_struct().unwrap().get("a").unwrap().as_sequence().unwrap();
+
+    // results are references into the queried document
+    let path = ionpath_parser::path("/a/*[b]").unwrap();
+    let results = path.match_ref(&doc);
+    assert_eq!(results.len(), 2);
+    assert!(std::ptr::eq(results[0], items.get(0).unwrap()));
+    assert!(std::ptr::eq(results[1], items.get(1).unwrap()));
+
+    // and agree with the owned API
+    for q in ["/a/*[b]", "//b", "/a/-1/c", "/a/5", "/a/*[b > 1]/b"] {
+        let path = ionpath_parser::path(q).unwrap();
+        let borrowed: Vec<Element> = path.match_ref(&doc).into_iter().cloned().collect();
+        assert_eq!(borrowed, path.match_element(doc.clone()));
+    }
+}
+
+
+#[test]
+fn test_lazy_iteration() {
+    let doc = Element::read_one(r#"{
+        a: [ x::{ b: 1, c: [ 1, 2 ] }, { b: 2, c: [] }, y::{ b: 3, c: [ 3 ] } ],
+        d: { a: [ { b: 4 } ] }
+    }"#).unwrap();
+
+    // yields the same matches, in the same order, as eager evaluation
+    for q in [
+        "/a/*", "/a/*/b", "/a/-1:0:-1/b", "//b", "//a/*/b", "//c/*", "/a/*[c/*]/b",
+        "/a/x::*/c/0", "/a/*[b > 1][c/* >= 3]", "//*[b]/b", "/nothing/here", "//0",
+    ] {
+        let path = ionpath_parser::path(q).unwrap();
+        assert_eq!(path.iter(&doc).collect::<Vec<_>>(), path.match_ref(&doc), "{}", q);
+        assert_eq!(path.count(&doc), path.match_ref(&doc).len(), "{}", q);
+        assert_eq!(path.first(&doc), path.match_ref(&doc).first().copied(), "{}", q);
+        assert_eq!(path.any(&doc), !path.match_ref(&doc).is_empty(), "{}", q);
+    }
+
+    let path = ionpath_parser::path("//b").unwrap();
+    assert_eq!(path.iter(&doc).take(2).cloned().collect::<Vec<_>>(), elements("1 2"));
+    assert_eq!(path.first(&doc), Some(&Element::from(1)));
+    assert!(!ionpath_parser::path("//e").unwrap().any(&doc));
+}
+
+
+#[test]
+fn test_reader_queries() {
+    let stream = r#"
+        { id: 1, items: [ { sku: a, qty: 2 }, x::{ sku: b, qty: 5 }, { sku: c } ], meta: { id: 10 } }
+        header::{ version: 2, items: null.list }
+        { id: 3, items: [], nested: { items: [ { sku: d, qty: 1 } ] } }
+        5
+        [ { id: 4 }, ( { id: 5 } ) ]
+        { id: 6, items: [ { sku: e, qty: 3 }, { sku: f, qty: 4, tags: [ { sku: g } ] } ] }
+    "#;
+    let binary: Vec<u8> = elements(stream).iter().flat_map(|e| e.to_binary().unwrap()).collect();
+
+    // streaming evaluation finds the same matches as in-memory evaluation
+    for q in [
+        "/id", "/items/*/sku", "/items/0", "/items/1:", "/items/0:1", "/items/-1/sku", "/items/2:0:-1/sku",
+        "/items/x::*", "/items/*[qty > 2]/sku", "/items/*[qty]", "//sku", "//items/*/qty", "//id",
+        "//x::*/sku", "//*[qty >= 3]/sku", "/0/id", "/1/0/id", "//0", "//1:", "/*", "/missing",
+        "//items/*[tags/*/sku = g]/qty", "/meta/id", "/header::*",
+    ] {
+        let path = ionpath_parser::path(q).unwrap();
+        let expected = path.match_stream(&elements(stream));
+        let mut reader = ion_rs::ReaderBuilder::new().build(stream).unwrap();
+        assert_eq!(path.match_reader(&mut reader).unwrap(), expected, "{}", q);
+        let mut reader = ion_rs::ReaderBuilder::new().build(binary.as_slice()).unwrap();
+        assert_eq!(path.match_reader(&mut reader).unwrap(), expected, "{}", q);
+    }
+
+    // absolute predicate paths are evaluated against the current top-level value
+    let path = ionpath_parser::path("/items/*[/id = 6]/sku").unwrap();
+    let mut reader = ion_rs::ReaderBuilder::new().build(stream).unwrap();
+    assert_eq!(path.match_reader(&mut reader).unwrap(), vec![
+        StreamMatch { index: 5, element: Element::read_one("e").unwrap() },
+        StreamMatch { index: 5, element: Element::read_one("f").unwrap() },
+    ]);
+
+    // index and slice keys select the same items whether or not the sequence length is known
+    let lists = "[] [0] [0, 1] [0, 1, 2] [0, 1, 2, 3, 4, 5]";
+    for q in ["/0", "/2", "/-1", "/0:", "/1:", "/2:2", "/3:1", "/1:3", "/0::2", "/1::0", "/2::0", "/:2", "/-2:", "/1:4:2", "/4:1:-1"] {
+        let path = ionpath_parser::path(q).unwrap();
+        let mut reader = ion_rs::ReaderBuilder::new().build(lists).unwrap();
+        assert_eq!(path.match_reader(&mut reader).unwrap(), path.match_stream(&elements(lists)), "{}", q);
+    }
+
+    // bounds far outside of the sequence only visit its items
+    let list = "[0, 1, 2, 3, 4]";
+    assert_eq!(query("/0:2147483647", list), elements("0 1 2 3 4"));
+    assert_eq!(query("/0:2147483647:2", list), elements("0 2 4"));
+    assert_eq!(query("/-2147483648", list), elements("2"));
+    assert_eq!(query("/2147483647:0:-1", list), elements("4 3 2 1 0"));
+    assert_eq!(query("/2147483647:0:-3", list), elements("4 1"));
+    assert_eq!(query("/-2147483648:2147483647:2147483647", list), elements("2"));
+    assert_eq!(query("/2147483647:-2147483648:-2147483648", list), elements(""));
+    for q in ["/0:2147483647", "/0:2147483647:2", "/-2147483648", "/2147483647:0:-3", "/-2147483648:2147483647:2147483647"] {
+        let path = ionpath_parser::path(q).unwrap();
+        let mut reader = ion_rs::ReaderBuilder::new().build(lists).unwrap();
+        assert_eq!(path.match_reader(&mut reader).unwrap(), path.match_stream(&elements(lists)), "{}", q);
+    }
+
+    let mut reader = ion_rs::ReaderBuilder::new().build("{ a: [ 1, 2").unwrap();
+    assert!(ionpath_parser::path("/a/1").unwrap().match_reader(&mut reader).is_err());
+}
+
+
+#[test]
+fn test_match_locations() {
+    let doc = Element::read_one(r#"{
+        orders: [
+            { id: 1, lines: [ { sku: a }, { sku: b } ] },
+            { id: 2, lines: ( { sku: c } ) },
+        ],
+        'odd name': { 'quote"s': 3, '3': 4, '': 5, 'tab\t': 6, '$x_1': 7 },
+    }"#).unwrap();
+
+    let located = |q: &str| -> Vec<(String, Element)> {
+        ionpath_parser::path(q).unwrap().match_located(&doc).into_iter()
+            .map(|(loc, e)| (loc.to_string(), e.clone()))
+            .collect()
+    };
+    assert_eq!(located("/orders/*/id"), vec![
+        ("/orders/0/id".to_string(), Element::from(1)),
+        ("/orders/1/id".to_string(), Element::from(2)),
+    ]);
+    assert_eq!(located("//sku").into_iter().map(|(l, _)| l).collect::<Vec<_>>(), vec![
+        "/orders/0/lines/0/sku", "/orders/0/lines/1/sku", "/orders/1/lines/0/sku",
+    ]);
+    assert_eq!(located("/orders/-1/lines/*[sku = c]").into_iter().map(|(l, _)| l).collect::<Vec<_>>(), vec![
+        "/orders/1/lines/0",
+    ]);
+    assert_eq!(located("'odd name'/*").into_iter().map(|(l, _)| l).collect::<Vec<_>>(), vec![
+        r#"/"odd name"/"quote\"s""#, r#"/"odd name"/"3""#, r#"/"odd name"/"""#,
+        r#"/"odd name"/"tab\x09""#, r#"/"odd name"/$x_1"#,
+    ]);
+
+    // printed locations select the element they were found at
+    for (loc, element) in ionpath_parser::path("//*").unwrap().match_located(&doc) {
+        let found = ionpath_parser::path(&loc.to_string()).unwrap().match_ref(&doc);
+        assert_eq!(found, vec![element], "{}", loc);
+    }
+
+    assert_eq!(Location::default().to_string(), "/");
+    assert!(Location::default().is_root());
+    assert_eq!(Location::new(vec![Step::Field("a".into()), Step::Index(3)]).to_string(), "/a/3");
+}
+
+
+#[test]
+fn test_compiled_paths() {
+    let docs = elements(r#"
+        { a: [ { b: 1, c: x::2 }, { b: 3, bc: 4 } ], d: { b: 5 } }
+        { a: [ { b: 6 } ], 'a*': 7 }
+        [ 1, [ 2, { b: 8 } ], 3 ]
+    "#);
+    let queries = [
+        "/a/*/b", "//b", "/a/*/'b*'", "/a/0/'?'", "//x::*", "/a/*[b > 2]", "/a/*[/d/b = 5]/b",
+        "/*/-1", "/1:", "//*[b]/b", "/'a*'",
+    ];
+    for q in queries {
+        let path = ionpath_parser::path(q).unwrap();
+        let compiled = path.compile();
+        // the same compiled path can be evaluated against any number of documents
+        for doc in docs.iter() {
+            assert_eq!(compiled.match_ref(doc), path.match_ref(doc), "{}", q);
+            assert_eq!(compiled.iter(doc).collect::<Vec<_>>(), path.match_ref(doc), "{}", q);
+            assert_eq!(compiled.match_located(doc), path.match_located(doc), "{}", q);
+            assert_eq!(compiled.count(doc), path.count(doc), "{}", q);
+        }
+        assert_eq!(compiled.match_stream(&docs), path.match_stream(&docs), "{}", q);
+    }
+}
+
+
+#[test]
+fn test_query_set() {
+    let docs = elements(r#"
+        { a: [ { b: 1, c: x::2 }, { b: 3, bc: 4, a: [ { b: 9 } ] } ], d: { b: 5 } }
+        { a: [ { b: 6 } ], 'a*': 7 }
+        [ 1, [ 2, { b: 8 } ], 3 ]
+    "#);
+    let queries = [
+        "/a/*/b", "/a/*/c", "/a/*", "//b", "/a/*/'b*'", "//x::*", "/a/*[b > 2]/bc", "/a/*[/d/b = 5]/b",
+        "/*/-1", "/1:", "//*[b]/b", "/'a*'", "//a//b", "/a/*/b",
+    ];
+    let set: QuerySet = queries.iter().map(|q| ionpath_parser::path(q).unwrap()).collect();
+    assert_eq!(set.len(), queries.len());
+
+    for doc in docs.iter() {
+        let results = set.match_ref(doc);
+        for (query, q) in queries.iter().enumerate() {
+            let mut expected = set.get(query).unwrap().match_located(doc);
+            let mut found: Vec<_> = results.iter()
+                .filter(|m| m.query == query)
+                .map(|m| (m.location.clone(), m.element))
+                .collect();
+            // the set returns matches in document order, so only compare them as multisets
+            expected.sort_by_key(|(loc, _)| loc.to_string());
+            found.sort_by_key(|(loc, _)| loc.to_string());
+            assert_eq!(found, expected, "{}", q);
+        }
+    }
+
+    // results are in document order, and identical queries are both reported
+    let doc = Element::read_one("{ a: [ { b: 1 }, { b: 2 } ] }").unwrap();
+    let tagged: Vec<_> = set.match_ref(&doc).into_iter()
+        .filter(|m| m.query == 0 || m.query == 2 || m.query == 13)
+        .map(|m| (m.query, m.location.to_string()))
+        .collect();
+    assert_eq!(tagged, vec![
+        (2, "/a/0".to_string()), (0, "/a/0/b".to_string()), (13, "/a/0/b".to_string()),
+        (2, "/a/1".to_string()), (0, "/a/1/b".to_string()), (13, "/a/1/b".to_string()),
+    ]);
+}
+
+
+#[test]
+fn test_fallible_evaluation() {
+    let doc = Element::read_one(r#"{ items: [ { id: 1 }, { id: [1] }, { id: { n: 1 } }, { id: 3 } ] }"#).unwrap();
+    let path = ionpath_parser::path("/items/*[id > 0]").unwrap();
+
+    // comparing against a list or struct used to panic; by default it just doesn't match
+    let expected = elements("{ id: 1 } { id: 3 }");
+    assert_eq!(path.match_element(doc.clone()), expected);
+    assert_eq!(path.try_match_element(doc.clone(), EvalOptions::default()), Ok(expected.clone()));
+    assert_eq!(ionpath_parser::path("/items/*[id != 0]").unwrap().match_element(doc.clone()), expected);
+
+    match path.try_match_element(doc.clone(), EvalOptions::new().strict()) {
+        Err(EvalError::NonScalarComparison { found, location, .. }) => {
+            assert_eq!(found, ion_rs::IonType::List);
+            assert_eq!(location.to_string(), "/items/1/id");
+        },
+        other => panic!("expected an error, got {:?}", other),
+    }
+    // the strict error surfaces through every fallible entry point
+    let ctx = EvalContext::new(std::slice::from_ref(&doc)).with_options(EvalOptions::new().strict());
+    assert!(path.try_match_element_in(&ctx, &doc).is_err());
+    assert!(path.compile().try_match_element_in(&ctx, &doc).is_err());
+    assert_eq!(path.match_element_in(&ctx, &doc).len(), 2);
+    let mut iter = path.iter_in(&ctx, &doc);
+    assert_eq!(iter.by_ref().count(), 1);
+    assert!(iter.error().is_some());
+
+    let pred = crate::Predicate::Compare { path: None, op: crate::CompareOp::LessThan, value: crate::Literal::Integer(5.into()) };
+    let list = Element::read_one("[1, 2]").unwrap();
+    assert!(!pred.filter(&list));
+    assert_eq!(pred.try_filter(&list, EvalOptions::default()), Ok(false));
+    assert!(pred.try_filter(&list, EvalOptions::new().strict()).is_err());
+    assert_eq!(pred.try_filter(&Element::from(3), EvalOptions::new().strict()), Ok(true));
+    assert_eq!(crate::Literal::Integer(5.into()).partial_cmp(&list), None);
+
+    // scalars compare the same way in both modes
+    let scalars = Element::read_one("{ items: [ { id: 1 }, { id: 3 } ] }").unwrap();
+    assert_eq!(path.try_match_element(scalars.clone(), EvalOptions::new().strict()).unwrap().len(), 2);
+}
+
+
+#[test]
+fn test_evaluation_limits() {
+    let doc = Element::read_one(r#"{ a: { b: { c: { d: 1 } } }, list: [ 1, 2, 3, 4, 5, 6, 7, 8 ] }"#).unwrap();
+    let exceeded = |q: &str, options: EvalOptions| -> Option<Limit> {
+        match ionpath_parser::path(q).unwrap().try_match_element(doc.clone(), options) {
+            Err(EvalError::LimitExceeded { limit, .. }) => Some(limit),
+            Err(other) => panic!("unexpected error {:?}", other),
+            Ok(_) => None,
+        }
+    };
+
+    assert_eq!(exceeded("//d", EvalOptions::new().with_max_depth(4)), None);
+    assert_eq!(exceeded("//d", EvalOptions::new().with_max_depth(3)), Some(Limit::Depth));
+    assert_eq!(exceeded("/a/b", EvalOptions::new().with_max_depth(3)), None);
+    // predicates nested inside each other count towards the depth as well
+    assert_eq!(exceeded("/a[/a[/a[/a]]]", EvalOptions::new().with_max_depth(3)), None);
+    assert_eq!(exceeded("/a[/a[/a[/a[/a]]]]", EvalOptions::new().with_max_depth(3)), Some(Limit::Depth));
+
+    // /list/* visits the list and its 8 items
+    assert_eq!(exceeded("/list/*", EvalOptions::new().with_max_visited(9)), None);
+    assert_eq!(exceeded("/list/*", EvalOptions::new().with_max_visited(8)), Some(Limit::Visited));
+    assert_eq!(exceeded("/list/*[> 0]", EvalOptions::new().with_max_visited(8)), Some(Limit::Visited));
+    assert_eq!(exceeded("/list[*]", EvalOptions::new().with_max_visited(1)), Some(Limit::Visited));
+    assert_eq!(exceeded("//*", EvalOptions::new().with_max_visited(12)), Some(Limit::Visited));
+
+    assert_eq!(exceeded("/list/*", EvalOptions::new().with_max_results(8)), None);
+    assert_eq!(exceeded("/list/*", EvalOptions::new().with_max_results(7)), Some(Limit::Results));
+    assert_eq!(exceeded("/list/*/x", EvalOptions::new().with_max_context_size(8)), None);
+    assert_eq!(exceeded("/list/*/x", EvalOptions::new().with_max_context_size(7)), Some(Limit::ContextSize));
+    // 64 sums, each compared with 20
+    assert_eq!(exceeded("/list[* + * = 20]", EvalOptions::new().with_max_values(128)), None);
+    assert_eq!(exceeded("/list[* + * = 20]", EvalOptions::new().with_max_values(127)), Some(Limit::Values));
+    assert_eq!(exceeded("/list[contains(*, *)]", EvalOptions::new().with_max_values(63)), Some(Limit::Values));
+    // values multiply without visiting many elements
+    let items: Vec<String> = (0..150).map(|i| i.to_string()).collect();
+    let big = Element::read_one(format!("{{ a: {{ b: [ {} ] }} }}", items.join(", "))).unwrap();
+    let path = ionpath_parser::path("/a[b/* + b/* + b/* = -1]").unwrap();
+    let options = EvalOptions::new().with_max_visited(1000).with_max_values(10_000);
+    assert_eq!(path.try_match_element(big, options), Err(EvalError::LimitExceeded { limit: Limit::Values, max: 10_000 }));
+
+    // without limits, the same queries run to completion
+    assert_eq!(ionpath_parser::path("//*").unwrap().try_match_element(doc.clone(), EvalOptions::default()).unwrap().len(), 13);
+
+    // the lazy iterator and query sets are limited too
+    let options = EvalOptions::new().with_max_results(3);
+    let ctx = EvalContext::new(std::slice::from_ref(&doc)).with_options(options);
+    let path = ionpath_parser::path("/list/*").unwrap();
+    let mut iter = path.iter_in(&ctx, &doc);
+    assert_eq!(iter.by_ref().count(), 3);
+    assert_eq!(iter.error(), Some(&EvalError::LimitExceeded { limit: Limit::Results, max: 3 }));
+    let set: QuerySet = ["/list/*", "/a"].iter().map(|q| ionpath_parser::path(q).unwrap()).collect();
+    assert!(set.try_match_ref(&doc, options).is_err());
+    assert!(set.try_match_ref(&doc, EvalOptions::new().with_max_results(9)).is_ok());
+    assert_eq!(set.match_ref(&doc).len(), 9);
+
+    // and so are streams, with the limits applying to the whole stream
+    let stream = "{ a: 1 } { a: 2 } { a: 3, b: [ { a: 4 } ] }";
+    let exceeded = StreamError::Eval(EvalError::LimitExceeded { limit: Limit::Results, max: 2 });
+    for q in ["/a", "/a[> 0]", "//a", "/a/../a"] {
+        let path = ionpath_parser::path(q).unwrap();
+        let options = EvalOptions::new().with_max_results(2);
+        assert_eq!(path.try_match_stream(&elements(stream), options), Err(EvalError::LimitExceeded { limit: Limit::Results, max: 2 }), "{}", q);
+        assert_eq!(path.try_match_bytes(stream, options).unwrap_err().to_string(), exceeded.to_string(), "{}", q);
+        let mut reader = ion_rs::ReaderBuilder::new().build(stream).unwrap();
+        assert!(matches!(path.try_match_reader(&mut reader, options), Err(StreamError::Eval(EvalError::LimitExceeded { limit: Limit::Results, .. }))), "{}", q);
+        let mut reader = ion_rs::ReaderBuilder::new().build(stream).unwrap();
+        let options = EvalOptions::new().with_max_results(4);
+        assert_eq!(path.try_match_reader(&mut reader, options).unwrap(), path.try_match_stream(&elements(stream), options).unwrap(), "{}", q);
+    }
+    // each value only visits one element in memory, but the stream visits two
+    let stream = "{ b: [ { a: 1 } ] } { b: [ { a: 2 } ] }";
+    let path = ionpath_parser::path("/b/*[a]").unwrap();
+    let mut reader = ion_rs::ReaderBuilder::new().build(stream).unwrap();
+    assert!(matches!(path.try_match_reader(&mut reader, EvalOptions::new().with_max_visited(1)), Err(StreamError::Eval(_))));
+    let mut reader = ion_rs::ReaderBuilder::new().build(stream).unwrap();
+    assert_eq!(path.try_match_reader(&mut reader, EvalOptions::new().with_max_visited(2)).unwrap().len(), 2);
+    assert!(matches!(path.try_match_bytes("{ a: ", EvalOptions::new()), Err(StreamError::Read(_))));
+}
+
+
+#[test]
+fn test_tracing() {
+    let doc = Element::read_one(r#"{
+        orders: [
+            { id: 1, state: open, total: 10 },
+            x::{ id: 2, state: open, total: 30 },
+            x::{ id: 3, state: closed, total: 50 },
+            x::{ id: 4, state: open },
+            x::[],
+        ]
+    }"#).unwrap();
+    let path = ionpath_parser::path("/orders/x::*[state = open][total > 20 or total < 5]/id").unwrap();
+
+    let mut events = Vec::new();
+    let results = path.match_traced(&doc, &mut |event: &SegmentEvent| {
+        let locations = |filtered: &[(Location, &Element)]| filtered.iter().map(|(loc, _)| loc.to_string()).collect::<Vec<_>>();
+        events.push((
+            event.index,
+            event.input,
+            event.candidates,
+            locations(&event.filtered_by_annotations),
+            event.filtered_by_predicates.iter().map(|list| locations(list)).collect(),
+            event.output,
+        ));
+    });
+    assert_eq!(results, path.match_ref(&doc));
+    assert_eq!(results, vec![&Element::from(2)]);
+    assert_eq!(events, vec![
+        (0, 1, 1, vec![], vec![], 1),
+        (1, 1, 5, vec!["/orders/0".to_string()], vec![
+            vec!["/orders/2".to_string(), "/orders/4".to_string()],
+            vec!["/orders/3".to_string()],
+        ], 1),
+        (2, 1, 1, vec![], vec![], 1),
+    ]);
+
+    // segments after the context became empty are still reported
+    let mut outputs = Vec::new();
+    ionpath_parser::path("/missing/a/b").unwrap().match_traced(&doc, &mut |event: &SegmentEvent| {
+        outputs.push((event.segment.key.clone(), event.input, event.output));
+    });
+    assert_eq!(outputs, vec![
+        (crate::Key::Symbol("missing".into()), 1, 0),
+        (crate::Key::Symbol("a".into()), 0, 0),
+        (crate::Key::Symbol("b".into()), 0, 0),
+    ]);
+}
+
+
+#[test]
+fn test_explain() {
+    let doc = Element::read_one(r#"{
+        orders: [
+            { id: 1, state: open, total: 10 },
+            x::{ id: 2, state: open, total: 3 },
+            x::{ id: 3, state: closed, total: 50 },
+        ]
+    }"#).unwrap();
+    let explained = |q: &str| explain(&ionpath_parser::path(q).unwrap(), &doc);
+    let near = |q: &str| -> Vec<String> {
+        explained(q).failure.unwrap().near_matches.iter().map(|(loc, _)| loc.to_string()).collect()
+    };
+
+    let matched = explained("/orders/*/id");
+    assert_eq!(matched.matches, 3);
+    assert_eq!(matched.failure, None);
+    assert_eq!(matched.to_string(), "matched 3 element(s)");
+
+    let failure = explained("/orders/*/sku").failure.unwrap();
+    assert_eq!((failure.index, failure.input, failure.candidates, failure.stage), (2, 3, 0, Stage::Key));
+    assert_eq!(near("/orders/*/sku"), vec!["/orders/0", "/orders/1", "/orders/2"]);
+
+    let failure = explained("/orders/y::*").failure.unwrap();
+    assert_eq!((failure.index, failure.candidates, failure.stage), (1, 3, Stage::Annotations));
+    assert_eq!(near("/orders/y::*"), vec!["/orders/0", "/orders/1", "/orders/2"]);
+
+    let failure = explained("/orders/*/id:string").failure.unwrap();
+    assert_eq!((failure.index, failure.candidates, failure.stage), (2, 3, Stage::Type));
+    assert_eq!(near("/orders/*/id:string"), vec!["/orders/0/id", "/orders/1/id", "/orders/2/id"]);
+
+    // the last candidates were removed by the second predicate, after the first one
+    // already removed the others
+    let failure = explained("/orders/*[state = open][total > 20]/id").failure.unwrap();
+    assert_eq!((failure.index, failure.stage), (1, Stage::Predicates(1)));
+    assert_eq!(near("/orders/*[state = open][total > 20]/id"), vec!["/orders/0", "/orders/1"]);
+    assert_eq!(near("/orders/x::*[state = open][total > 20]/id"), vec!["/orders/1"]);
+    assert_eq!(explained("/orders/x::*[state = open][total > 20]/id").to_string(),
+        "no matches: segment 1: 3 element(s) matched the key, the last removed by predicate 1\n  near match at /orders/1: x::{id: 2, state: open, total: 3}");
+    assert_eq!(explained("/orders/*/sku").to_string().lines().next(),
+        Some("no matches: segment 2: none of 3 element(s) had a child matching the key"));
+}
+
+
+#[test]
+fn test_parent_steps() {
+    let doc = r#"{
+        orders: [
+            { id: 1, items: [ { sku: "A" }, { sku: "X" } ] },
+            { id: 2, items: [ { sku: "B" } ] },
+            { id: 3, items: [ { sku: "X" }, { sku: "X" } ] },
+        ]
+    }"#;
+
+    // the orders whose line items have sku X, each only once
+    assert_eq!(query(r#"//items/*[sku="X"]/../.."#, doc), elements(r#"
+        { id: 1, items: [ { sku: "A" }, { sku: "X" } ] }
+        { id: 3, items: [ { sku: "X" }, { sku: "X" } ] }
+    "#));
+    assert_eq!(query("/orders/*/items/*/../../../*/id", doc), elements("1 2 3"));
+    assert_eq!(query("/a/b/..", "{a: {b: 1, b: 2}}"), elements("{b: 1, b: 2}"));
+    assert_eq!(query(r#"//sku[= "B"]/../../../id"#, doc), elements("2"));
+    assert_eq!(query("/orders/0/..", doc), query("/orders", doc));
+    // the element evaluation started at has no parent
+    assert_eq!(query("..", doc), elements(""));
+    assert_eq!(query("/orders/../..", doc), elements(""));
+
+    // in predicates, `..` moves up from the element being filtered
+    assert_eq!(query(r#"//sku[../../../id = 3]"#, doc), elements(r#""X" "X""#));
+    assert_eq!(query("/orders/*/items/*[../1]/sku", doc), elements(r#""A" "X" "X" "X""#));
+    assert_eq!(query("/orders/*[items/*[../../id > 1][sku = \"X\"]]/id", doc), elements("3"));
+
+    let root = Element::read_one(doc).unwrap();
+    let located: Vec<String> = ionpath_parser::path("//sku/..").unwrap().match_located(&root).into_iter()
+        .map(|(loc, _)| loc.to_string())
+        .collect();
+    assert_eq!(located, vec!["/orders/0/items/0", "/orders/0/items/1", "/orders/1/items/0", "/orders/2/items/0", "/orders/2/items/1"]);
+
+    // every engine agrees
+    let stream = r#"{ a: { b: 1 } } { a: [ { b: 2 } ] }"#;
+    for q in ["//b/..", "/a/*[../b]", "//b/../..", "/a/b[../../a]"] {
+        assert_engines_agree(q, stream);
+    }
+    for q in ["/orders/*/items/*/..", "//sku/../../../id", "/orders/0", "/orders/*/items/*/../../../*/id"] {
+        assert_engines_agree(q, doc);
+    }
+}
+
+#[test]
+fn test_axes() {
+    let doc = r#"{
+        orders: [
+            { id: 1, status: "ok", items: [ { sku: "A" }, { sku: "X" } ] },
+            { id: 2, status: "err", items: [ { sku: "B" } ] },
+            { id: 3, status: "ok", items: [ { sku: "X" }, { sku: "X" }, { sku: "C" } ] },
+        ]
+    }"#;
+
+    // ancestors, in document order, matched by their name or index in their parent
+    assert_eq!(query(r#"/orders/0/items/1/sku/ancestor::*/id"#, doc), elements("1"));
+    assert_eq!(query(r#"/orders/2/items/0/sku/ancestor::orders"#, doc), query("/orders", doc));
+    assert_eq!(query(r#"/orders/1/items/0/ancestor::1"#, doc), query("/orders/1", doc));
+    assert_eq!(query("/orders/0/ancestor::*", doc), [elements(doc), query("/orders", doc)].concat());
+    assert_eq!(query(r#"//sku[= "B"]/ancestor::*[id]/status"#, doc), elements(r#""err""#));
+
+    // siblings, in document order, in sequences and structs
+    assert_eq!(query("/orders/0/following-sibling::*/id", doc), elements("2 3"));
+    assert_eq!(query("/orders/2/preceding-sibling::*/id", doc), elements("1 2"));
+    assert_eq!(query("/orders/1/following-sibling::0", doc), elements(""));
+    assert_eq!(query("/orders/1/preceding-sibling::0/id", doc), elements("1"));
+    assert_eq!(query("/orders/0/id/following-sibling::*", doc), [query("/orders/0/status", doc), query("/orders/0/items", doc)].concat());
+    assert_eq!(query("/orders/0/items/preceding-sibling::id", doc), elements("1"));
+    assert_eq!(query("/orders/*/status[= \"err\"]/following-sibling::items/*/sku", doc), elements(r#""B""#));
+    // the element evaluation started at has no siblings
+    assert_eq!(query("following-sibling::*", doc), elements(""));
+
+    // elements reached from several context nodes are only returned once
+    assert_eq!(query(r#"/orders/*/items/*[sku = "X"]/ancestor::*"#, doc), [
+        elements(doc), query("/orders", doc),
+        query("/orders/0", doc), query("/orders/0/items", doc),
+        query("/orders/2", doc), query("/orders/2/items", doc),
+    ].concat());
+    assert_eq!(query("/orders/*/following-sibling::*/id", doc), elements("2 3"));
+    assert_eq!(query("/orders/*/preceding-sibling::*/id", doc), elements("1 2"));
+    assert_eq!(query("/orders/2/items/*/preceding-sibling::*/sku", doc), elements(r#""X" "X""#));
+    // and are only visited once, so this doesn't take time quadratic in the depth
+    let deep = (0..100).fold(Element::from(0), |inner, _| ion_rs::element::Struct::builder().with_field("a", inner).build().into());
+    let path = ionpath_parser::path("//*/ancestor::*").unwrap();
+    assert_eq!(path.try_match_element(deep, EvalOptions::new().with_max_visited(300)).unwrap().len(), 100);
+
+    // in predicates: items followed by another item with the same sku
+    assert_eq!(query(r#"//items/*[following-sibling::*/sku = "X"]/sku"#, doc), elements(r#""A" "X""#));
+    assert_eq!(query(r#"/orders/*[preceding-sibling::*/status = "err"]/id"#, doc), elements("3"));
+
+    // `descendant::` is `//`
+    assert_eq!(query("/orders/descendant::sku", doc), query("/orders//sku", doc));
+
+    // every engine agrees
+    let stream = r#"{ a: { b: 1, c: 2 } } { a: [ { b: 2 }, 3 ] }"#;
+    for q in ["//b/ancestor::*", "/a/*[following-sibling::c]", "//c/preceding-sibling::*", "/a/descendant::b"] {
+        assert_engines_agree(q, stream);
+    }
+    for q in ["//sku/ancestor::items", "/orders/0/following-sibling::*/id", "/orders/descendant::id"] {
+        assert_engines_agree(q, doc);
+    }
+}
+
+#[test]
+fn test_unions() {
+    let doc = Element::read_one(r#"{ a: { b: 1, x: 2 }, c: [ { d: 3, e: 2 }, { d: 4, e: 0 } ], f: 5 }"#).unwrap();
+    let union = |q: &str| ionpath_parser::union(q).unwrap().match_ref(&doc).into_iter().cloned().collect::<Vec<_>>();
+
+    assert_eq!(union("/a/b | /c/*/d[../e > 1]"), elements("1 3"));
+    // document order, not the order of the paths
+    assert_eq!(union("/f | /c/*/d | /a/x"), elements("2 3 4 5"));
+    // ancestors before descendants
+    assert_eq!(union("//d | /c/1 | /c"), elements("[ { d: 3, e: 2 }, { d: 4, e: 0 } ] 3 { d: 4, e: 0 } 4"));
+    // duplicates are removed
+    assert_eq!(union("/c/*/d | //d | /c/0/d"), elements("3 4"));
+    assert_eq!(union("//*[= 2]"), elements("2 2"));
+    assert_eq!(union("/nothing | /f"), elements("5"));
+
+    let located: Vec<String> = ionpath_parser::union("/f | /a/*").unwrap().match_located(&doc).into_iter()
+        .map(|(loc, _)| loc.to_string())
+        .collect();
+    assert_eq!(located, vec!["/a/b", "/a/x", "/f"]);
+
+    // a union can be evaluated again, against other documents
+    let reused = ionpath_parser::union("/f | //d").unwrap();
+    assert_eq!(reused.match_ref(&doc), vec![&Element::from(3), &Element::from(4), &Element::from(5)]);
+    assert_eq!(reused.match_element(Element::read_one("{ f: 1, d: 2 }").unwrap()), elements("1 2"));
+    assert_eq!(reused, ionpath_parser::union("/f | //d").unwrap());
+
+    // relative paths start at the given element, absolute ones at the root
+    let ctx = EvalContext::new(std::slice::from_ref(&doc));
+    let c = doc.as_struct().unwrap().get("c").unwrap();
+    assert_eq!(ionpath_parser::union("*/e | /f").unwrap().match_element_in(&ctx, c), vec![&Element::from(2), &Element::from(0), &Element::from(5)]);
+
+    // limits apply to all paths together
+    let options = EvalOptions::new().with_max_results(2);
+    let two = ionpath_parser::union("/a/b | /f").unwrap();
+    assert_eq!(two.try_match_element(doc.clone(), options), Ok(elements("1 5")));
+    let three = ionpath_parser::union("/a/b | /f | /c/0/d").unwrap();
+    assert_eq!(three.try_match_element(doc, options), Err(EvalError::LimitExceeded { limit: Limit::Results, max: 2 }));
+}
+
+#[test]
+fn test_key_choices() {
+    let doc = r#"{
+        books: [
+            { name: "a", year: 2001 },
+            { title: "b", year: 2002 },
+            { name: "c", title: "d" },
+            { label: "e" },
+        ]
+    }"#;
+
+    assert_eq!(query("/books/*/(name|title)", doc), elements(r#""a" "b" "c" "d""#));
+    assert_eq!(query("/books/(0|-1)", doc), elements(r#"{ name: "a", year: 2001 } { label: "e" }"#));
+    // items come in document order, each once
+    assert_eq!(query("/books/(-1|0|-1:0:-1)/(label|year)", doc), elements(r#"2001 2002 "e""#));
+    assert_eq!(query("/books/(1:2|3)/*", doc), elements(r#""b" 2002 "c" "d" "e""#));
+    // globs and mixed field names and indices
+    assert_eq!(query("/books/(0|'l*')/('n*'|year)", doc), elements(r#""a" 2001"#));
+    assert_eq!(query("/(books|0)/(5|nothing)", doc), elements(""));
+    assert_eq!(query(r#"/books/*[(name|label) = "e"]/label"#, doc), elements(r#""e""#));
+    assert_eq!(query("//(title|label)", doc), elements(r#""b" "d" "e""#));
+
+    // every engine agrees
+    let stream = r#"{ a: [1, 2, 3], b: { c: 4 } } [5, 6]"#;
+    for q in ["/(a|b)/(0|c)", "/(0|-1)", "/(a|b)/(0::2|-1)", "//(c|1)"] {
+        assert_engines_agree(q, stream);
+    }
+    for q in ["/books/(0|-1)/name", "/books/*/(name|title)", "/books/(0|2)/(name|year)"] {
+        assert_engines_agree(q, doc);
+    }
+}
+
+#[test]
+fn test_regexes() {
+    let doc = r#"{
+        attr_1: a, attr_22: b, attr_x: c, my_attr_3: d, ATTR_4: e,
+        items: [ v1::f, v12::g, version::h, x::v3::i, j ],
+    }"#;
+
+    assert_eq!(query(r#"/~"^attr_[0-9]+$""#, doc), elements("a b"));
+    // the whole name has to match
+    assert_eq!(query(r#"/~"attr_[0-9]""#, doc), elements("a"));
+    assert_eq!(query(r#"/~"attr_[0-9]+""#, doc), elements("a b"));
+    assert_eq!(query(r#"/~".*attr_[0-9]""#, doc), elements("a d"));
+    assert_eq!(query(r#"/~"attr_1|attr_2""#, doc), elements("a"));
+    assert_eq!(query(r#"/~"(?i)^attr_\\d+$""#, doc), elements("a b e"));
+    assert_eq!(query(r#"/(~".*_x"|items)/0"#, doc), elements("v1::f"));
+
+    assert_eq!(query(r#"/items/~"^v[0-9]+$"::*"#, doc), elements("v1::f v12::g x::v3::i"));
+    assert_eq!(query(r#"/items/(~"v1.*"|x)::*"#, doc), elements("v1::f v12::g x::v3::i"));
+    assert_eq!(query(r#"/items/(~"v1"|x)::*"#, doc), elements("v1::f x::v3::i"));
+    assert_eq!(query(r#"/items/x::~"v."::*"#, doc), elements("x::v3::i"));
+    assert_eq!(query(r#"/items/~"v"::*"#, doc), elements(""));
+    assert_eq!(query(r#"/items/~"^version$"::*"#, doc), elements("version::h"));
+
+    // invalid regular expressions never match
+    let path = Path { absolute: true, segments: vec![Segment::new(false, Key::Regex("(".into()))].into() };
+    assert_eq!(path.match_element(Element::read_one(doc).unwrap()), elements(""));
+    // including ones that would be valid once anchored
+    let path = Path { absolute: true, segments: vec![Segment::new(false, Key::Regex("attr_1)|(.*".into()))].into() };
+    assert_eq!(path.match_element(Element::read_one(doc).unwrap()), elements(""));
+    let seg = Segment::new(false, Key::Symbol("*".into())).with_annotation_list(vec![AnnotationKey::Regex("^v[0-9]$".into())]);
+    assert!(seg.match_annotations(&Element::read_one("v1::2").unwrap()));
+    assert!(!seg.match_annotations(&Element::read_one("v12::2").unwrap()));
+    let seg = Segment::new(false, Key::Symbol("*".into())).with_annotation_list(vec![AnnotationKey::Regex("v[0-9]".into())]);
+    assert!(seg.match_annotations(&Element::read_one("v1::2").unwrap()));
+    assert!(!seg.match_annotations(&Element::read_one("v12::2").unwrap()));
+    assert!(!seg.match_annotations(&Element::read_one("xv1::2").unwrap()));
+
+    // every engine agrees
+    let stream = r#"{ a1: 1, b: v1::2 } { a22: [ v2::3 ] }"#;
+    for q in [r#"/~"^a[0-9]+$""#, r#"//~"v.*"::*"#, r#"/~"a.*"/~"v."::*"#, r#"//~"a2"/*"#] {
+        assert_engines_agree(q, stream);
+    }
+}
+
+#[test]
+fn test_literal_keys() {
+    let doc = r#"{ '*': 1, 'a?b': 2, axb: 3, a: 4, 'a*': 5, a: 6 }"#;
+
+    // quoted strings match exactly
+    assert_eq!(query(r#"/"*""#, doc), elements("1"));
+    assert_eq!(query(r#"/"a?b""#, doc), elements("2"));
+    assert_eq!(query(r#"/"a*""#, doc), elements("5"));
+    assert_eq!(query(r#"/"a""#, doc), elements("4 6"));
+    // symbols are still patterns
+    assert_eq!(query("/*", doc), elements("1 2 3 4 5 6"));
+    assert_eq!(query("/'a?b'", doc), elements("2 3"));
+    assert_eq!(query("/'a*'", doc), elements("2 3 4 5 6"));
+    assert_eq!(query(r#"/("*"|'a?b')"#, doc), elements("1 2 3"));
+    assert_eq!(query(r#"//"*"[> 0]"#, doc), elements("1"));
+
+    assert!(!Key::String("*".into()).is_pattern());
+    assert!(!Key::Symbol("a".into()).is_pattern());
+    assert!(Key::Symbol("a?".into()).is_pattern());
+    assert!(Key::Choice(vec![Key::String("a*".into()), Key::Regex("a".into())]).is_pattern());
+
+    // exact names found by direct lookup keep their locations
+    let doc = Element::read_one(r#"{ x: { a: 1, b: 2, a: 3 }, y: [ { a: 4 }, { b: 5 } ] }"#).unwrap();
+    let located: Vec<String> = ionpath_parser::path(r#"//"a""#).unwrap().match_located(&doc).into_iter()
+        .map(|(loc, _)| loc.to_string())
+        .collect();
+    assert_eq!(located, vec!["/x/a", "/x/a", "/y/0/a"]);
+    assert_eq!(query("/x/a/following-sibling::*", &doc.to_string()), elements("2 3"));
+    assert_eq!(query("/x/b/preceding-sibling::a", &doc.to_string()), elements("1"));
+    assert_eq!(query("/x/a/preceding-sibling::*", &doc.to_string()), elements("1 2"));
+
+    // every engine agrees
+    let stream = r#"{ '*': 1, a: 2 } { 'a?': { '*': 3 } }"#;
+    for q in [r#"/"*""#, r#"/"a?"/"*""#, r#"//"*""#, "/'a?'/*"] {
+        assert_engines_agree(q, stream);
+    }
+    let set: QuerySet = [r#"/"*""#, "/'a?'"].iter().map(|q| ionpath_parser::path(q).unwrap()).collect();
+    let doc = Element::read_one(r#"{ '*': 1, 'a?': 2, ab: 3 }"#).unwrap();
+    let found: Vec<(usize, &Element)> = set.match_ref(&doc).into_iter().map(|m| (m.query, m.element)).collect();
+    assert_eq!(found, vec![(0, &Element::from(1)), (1, &Element::from(2)), (1, &Element::from(3))]);
+}
+
+#[test]
+fn test_name_matching() {
+    // "é" composed (NFC) and decomposed (NFD)
+    let doc = "{ UserId: 1, userId: 2, userid: 3, 'caf\\u00e9': 4, 'cafe\\u0301': 5, 'CAF\\u00c9': 6, list: [ Tag::a, tag::b, TAG::c ] }";
+
+    assert_eq!(query("/userid", doc), elements("3"));
+    assert_eq!(query("/userid~i", doc), elements("1 2 3"));
+    assert_eq!(query("/'USER*'~i", doc), elements("1 2 3"));
+    assert_eq!(query(r#"/~"user.*"~i"#, doc), elements("1 2 3"));
+    assert_eq!(query(r#"/("USERID"|list)~i/0"#, doc), elements("Tag::a"));
+    assert_eq!(query(r#"/"caf\u00e9""#, doc), elements("4"));
+    assert_eq!(query(r#"/"caf\u00e9"~n"#, doc), elements("4 5"));
+    assert_eq!(query(r#"/"cafe\u0301"~n"#, doc), elements("4 5"));
+    assert_eq!(query(r#"/"caf\u00e9"~in"#, doc), elements("4 5 6"));
+    assert_eq!(query(r#"/~"^caf\u00e9$"~n"#, doc), elements("4 5"));
+
+    // annotations too
+    assert_eq!(query("/list/tag::*", doc), elements("tag::b"));
+    assert_eq!(query("/list/tag::*~i", doc), elements("Tag::a tag::b TAG::c"));
+    assert_eq!(query(r#"/list/(~"T.*"|x)::*~i"#, doc), elements("Tag::a tag::b TAG::c"));
+    // only the segment with the flag
+    assert_eq!(query("/LIST~i/tag::*", doc), elements("tag::b"));
+    assert_eq!(query("/list/*[/userid~i > 2]", doc), query("/list/*", doc));
+
+    // for the whole evaluation
+    let root = Element::read_one(doc).unwrap();
+    let everywhere = EvalOptions::new().with_name_matching(NameMatching::new().ignore_case().normalize());
+    let path = ionpath_parser::path(r#"/"CAF\u00e9""#).unwrap();
+    assert_eq!(path.try_match_element(root.clone(), everywhere), Ok(elements("4 5 6")));
+    assert_eq!(path.match_element(root.clone()), elements(""));
+    assert_eq!(ionpath_parser::path("/LIST/TAG::*").unwrap().try_match_element(root.clone(), everywhere), Ok(elements("Tag::a tag::b TAG::c")));
+    let path = ionpath_parser::path("/LIST[/USERID = 1][TAG::*]").unwrap();
+    assert_eq!(path.try_match_element(root.clone(), everywhere), Ok(query("/list", doc)));
+    let ctx = EvalContext::new(std::slice::from_ref(&root)).with_options(everywhere);
+    let found: Vec<&Element> = ionpath_parser::path("/USERID").unwrap().compile().iter_in(&ctx, &root).collect();
+    assert_eq!(found, vec![&Element::from(1), &Element::from(2), &Element::from(3)]);
+    let set: QuerySet = ["/USERID", "/List/tag::*"].iter().map(|q| ionpath_parser::path(q).unwrap()).collect();
+    assert_eq!(set.try_match_ref(&root, everywhere).unwrap().len(), 6);
+
+    // every engine agrees
+    let stream = r#"{ Ab: 1, aB: tag::2 } { ab: [ TAG::3 ] }"#;
+    for q in ["/ab~i", "/AB~i/TAG::*~i", "//tag::*~i"] {
+        assert_engines_agree(q, stream);
+    }
+    // including with the option for the whole evaluation
+    let stream = r#"{ UserId: 1 } { userid: 2 } { USERID: [ TAG::3 ] }"#;
+    for q in ["/userid", "/USERID/tag::*", "//Tag::*"] {
+        let path = ionpath_parser::path(q).unwrap();
+        let mut reader = ion_rs::ReaderBuilder::new().build(stream).unwrap();
+        let found = path.try_match_reader(&mut reader, everywhere).unwrap();
+        assert_eq!(found, path.try_match_stream(&elements(stream), everywhere).unwrap(), "{}", q);
+        assert_ne!(found, Vec::new(), "{}", q);
+    }
+    let mut reader = ion_rs::ReaderBuilder::new().build(stream).unwrap();
+    let found = ionpath_parser::path("/userid").unwrap().try_match_reader(&mut reader, everywhere).unwrap();
+    assert_eq!(found.iter().map(|m| m.index).collect::<Vec<_>>(), vec![0, 1, 2]);
+}
+
+#[test]
+fn test_positions() {
+    let doc = r#"{
+        events: [
+            { type: "ok", n: 0 }, { type: "err", n: 1 }, { type: "err", n: 2 },
+            { type: "ok", n: 3 }, { type: "err", n: 4 }, { type: "err", n: 5 },
+        ],
+        settings: { a: 1, b: 2, c: 3 },
+    }"#;
+
+    // zero-based, relative to the enclosing sequence
+    assert_eq!(query("/events/*[position() = 0]/n", doc), elements("0"));
+    assert_eq!(query("/events/*[position() = last()]/n", doc), elements("5"));
+    assert_eq!(query("/events/*[position() > last() - 3]/n", doc), elements("3 4 5"));
+    assert_eq!(query(r#"/events/*[position() > last() - 3][type = "err"]/n"#, doc), elements("4 5"));
+    assert_eq!(query("/events/*[position() >= 1 + 1][position() < 4]/n", doc), elements("2 3"));
+    assert_eq!(query("/events/*[n != position()]", doc), elements(""));
+    assert_eq!(query("/events/*[position() = 0 or position() = last()]/n", doc), elements("0 5"));
+    // after recursive descent, the position in each element's own parent
+    assert_eq!(query("//*[position() = last()][type]/n", doc), elements("5"));
+    // in structs, the position of the field
+    assert_eq!(query("/settings/*[position() = 1]", doc), elements("2"));
+    assert_eq!(query("/settings/*[last() = 2][position() > 0]", doc), elements("2 3"));
+    assert_eq!(query("*[last() = 1][position() = 1]", doc), query("/settings", doc));
+    // the element evaluation started at has no parent
+    assert_eq!(query("/events/..[position() = 0][last() = 0]", doc), elements(doc));
+
+    // values of paths take part in arithmetic
+    assert_eq!(query("/events/*[n - 1 = position() - 1]/n", doc), query("/events/*/n", doc));
+    assert_eq!(query("/events/*[n + 0.5 > 4]/n", doc), elements("4 5"));
+    assert_eq!(query("/events/*[n + 1e0 <= 2]/n", doc), elements("0 1"));
+    assert_eq!(query("/events/*[n = /settings/c]/n", doc), elements("3"));
+    // containers have no value to compare
+    assert_eq!(query("/events/*[/settings = n]", doc), elements(""));
+    let path = ionpath_parser::path("/events/*[/settings > n]").unwrap();
+    assert!(matches!(path.try_match_element(Element::read_one(doc).unwrap(), EvalOptions::new().strict()), Err(EvalError::NonScalarComparison { .. })));
+
+    // every engine agrees
+    let stream = r#"[ 1, 2, 3 ] { a: [ 4, 5 ] } [ 6 ]"#;
+    for q in ["/*[position() = last()]", "//*[position() > 0]", "/a/*[position() = 0]"] {
+        assert_engines_agree(q, stream);
+    }
+    for q in ["/events/*[position() = last()]/n", "//*[position() = 1]"] {
+        assert_engines_agree(q, doc);
+    }
+}
+
+#[test]
+fn test_functions() {
+    let doc = r#"{
+        users: [
+            { name: "Ann", tags: [ a, b, c ], score: -2.5, avatar: {{ aGVsbG8= }} },
+            { name: "bob", tags: [], score: 3.5e0, nick: Bobby },
+            { name: "Cleo", tags: [ a ], score: 7 },
+        ]
+    }"#;
+
+    // lengths of strings, symbols, lobs and sequences
+    assert_eq!(query("/users/*[length(name) = 3]/name", doc), elements(r#""Ann" "bob""#));
+    assert_eq!(query("/users/*/name[length() > 3]", doc), elements(r#""Cleo""#));
+    assert_eq!(query("/users/*[length(nick) = 5]/name", doc), elements(r#""bob""#));
+    assert_eq!(query("/users/*[length(avatar) = 5]/name", doc), elements(r#""Ann""#));
+    assert_eq!(query("/users/*/tags[length() = 0]", doc), elements("[]"));
+    // counting and existence
+    assert_eq!(query("/users/*[count(tags/*) >= 1]/name", doc), elements(r#""Ann" "Cleo""#));
+    assert_eq!(query("/users/*[count(*) = 4]/name", doc), elements(r#""Ann" "bob""#));
+    assert_eq!(query("/users/*[exists(nick)]/name", doc), elements(r#""bob""#));
+    assert_eq!(query("/users/*[exists(tags/*[= c]) or exists(nick)]/name", doc), elements(r#""Ann" "bob""#));
+    assert_eq!(query("/users/*[exists(nick) = false]/name", doc), elements(r#""Ann" "Cleo""#));
+    // text
+    assert_eq!(query(r#"/users/*[starts_with(name, "C")]/score"#, doc), elements("7"));
+    assert_eq!(query(r#"/users/*[ends_with(nick, "by")]/score"#, doc), elements("3.5e0"));
+    assert_eq!(query(r#"/users/*[contains(lower(name), "b")]/name"#, doc), elements(r#""bob""#));
+    assert_eq!(query(r#"/users/*[upper(name) = "ANN"]/score"#, doc), elements("-2.5"));
+    // symbols stay symbols, and compare with quoted symbols
+    assert_eq!(query(r#"/users/*[lower(nick) = "bobby"]"#, doc), elements(""));
+    assert_eq!(query("/users/*[lower(nick) = 'bobby']/name", doc), elements(r#""bob""#));
+    assert_eq!(query("/users/*[upper(nick) != 'BOBBY' or count(tags/*) = 1]/name", doc), elements(r#""Cleo""#));
+    assert_eq!(query("/a/*[lower(s) = 'active']/n", "{a: [{s: Active, n: 1}, {s: inactive, n: 2}, {s: ACTIVE, n: 3}]}"), elements("1 3"));
+    assert_eq!(query(r#"/users/*[starts_with(lower(nick), "bob")]/name"#, doc), elements(r#""bob""#));
+    assert_eq!(query("/users/*[starts_with(tags/*, \"b\")]/name", doc), elements(r#""Ann""#));
+    // numbers
+    assert_eq!(query("/users/*[abs(score) = 2.5]/name", doc), elements(r#""Ann""#));
+    assert_eq!(query("/users/*[floor(score) = -3]/name", doc), elements(r#""Ann""#));
+    assert_eq!(query("/users/*[round(score) = -3]/name", doc), elements(r#""Ann""#));
+    assert_eq!(query("/users/*[round(score) = 4e0]/name", doc), elements(r#""bob""#));
+    assert_eq!(query("/users/*[floor(score) = 3e0]/name", doc), elements(r#""bob""#));
+    assert_eq!(query("/users/*/score[abs() > 3][round() = 7]", doc), elements("7"));
+    let numbers = "[1d3, -25d-1, 5d-1, 4999d-4, -1d-2000000000, 1d-2000000000, 7d1000, 1d2000000000]";
+    assert_eq!(query("/*[floor() = round()]", numbers), elements("1d3 -25d-1 4999d-4 1d-2000000000 7d1000"));
+    assert_eq!(query("/*[floor() = -1]", numbers), elements("-1d-2000000000"));
+    assert_eq!(query("/*[round() = 0]", numbers), elements("4999d-4 -1d-2000000000 1d-2000000000"));
+    assert_eq!(query("/*[round() = -3]", numbers), elements("-25d-1"));
+    assert_eq!(query("/*[round() = 1]", numbers), elements("5d-1"));
+    // decimals too large to turn into an integer have no value
+    assert_eq!(query("/*[floor() >= 0]", numbers), elements("1d3 5d-1 4999d-4 1d-2000000000 7d1000"));
+    assert_eq!(query("/*[round() >= 0]", numbers), elements("1d3 5d-1 4999d-4 -1d-2000000000 1d-2000000000 7d1000"));
+    // values of the wrong type have no result
+    assert_eq!(query("/users/*[length(score) >= 0]", doc), elements(""));
+    assert_eq!(query("/users/*[abs(name) >= 0]", doc), elements(""));
+    assert_eq!(query(r#"/users/*[contains(tags, "a")]"#, doc), elements(""));
+    // and so do calls built without the argument the parser requires
+    let doc = Element::read_one(doc).unwrap();
+    for function in [Function::Count, Function::Exists] {
+        let seg = Segment::new(false, Key::Symbol("*".into())).with_predicate(PredicateExpr::Predicate(Predicate::Call(function, Vec::new())));
+        let path = Path { absolute: false, segments: vec![seg].into() };
+        assert_eq!(path.match_ref(&doc), Vec::<&Element>::new(), "{}", function);
+    }
+
+    // every engine agrees
+    let stream = r#"{ a: "xy", b: [ 1, 2 ] } { a: "z", c: 1 }"#;
+    for q in ["/*[length(a) = 2]", "/*[exists(c)]/a", "/*[count(b/*) = 0]", r#"/*[ends_with(a, "y")]/b"#] {
+        assert_engines_agree(q, stream);
+    }
+}
+
+#[test]
+fn test_type_tests() {
+    let doc = r#"{
+        values: [ 1, null.int, 2.5, "a", null, { b: 1 }, null.struct, [ 3 ], 2020-01-01T, 4 ],
+        nested: { when: 2021T, items: [ { when: 2022-06T } ] },
+    }"#;
+
+    // type filters on segments, typed nulls included
+    assert_eq!(query("/values/*:int", doc), elements("1 null.int 4"));
+    assert_eq!(query("/values/*:(string|decimal)", doc), elements(r#"2.5 "a""#));
+    assert_eq!(query("/values/*:struct/b", doc), elements("1"));
+    assert_eq!(query("/values/*:null", doc), elements("null"));
+    assert_eq!(query("//*:timestamp", doc), elements("2020-01-01T 2021T 2022-06T"));
+    assert_eq!(query("/values/-1:int", doc), elements("4"));
+    assert_eq!(query("/values/(0|2):int", doc), elements("1"));
+    assert_eq!(query("/values/*:int[= 4]", doc), elements("4"));
+    // `:` still starts slices
+    assert_eq!(query("/values/8:", doc), elements("2020-01-01T 4"));
+
+    // type() and is_null() in predicates
+    assert_eq!(query("/values/*[type() == struct]", doc), elements("{ b: 1 } null.struct"));
+    assert_eq!(query("/values/*[type() = list or type() = null]", doc), elements("null [ 3 ]"));
+    assert_eq!(query("/values/*[type() != int][type() != decimal][is_null()]", doc), elements("null null.struct"));
+    assert_eq!(query("/values/*:int[is_null() = false]", doc), elements("1 4"));
+    assert_eq!(query("/*[type(when) = timestamp]", doc), query("/nested", doc));
+    assert_eq!(query("/values/*[type() = int][is_null()]", doc), elements("null.int"));
+    assert_eq!(query(r#"/values/*[type() = "int"]"#, doc), elements(""));
+    assert_eq!(query("/values/*[is_null(b)]", doc), elements(""));
+    assert_eq!(query("/values/*[count(*:int) > 0]", doc), elements("{ b: 1 } [ 3 ]"));
+
+    // every engine agrees
+    let stream = r#"{ a: 1, b: null.int } { a: "x", b: [ 2 ] } [ 3, null ]"#;
+    for q in ["/a:int", "/*:(int|list)", "//*:int", "/*[type() = list]", "/b[is_null()]"] {
+        assert_engines_agree(q, stream);
+    }
+    for q in ["/values/*:int", "//*:timestamp", "/values/*[type() = struct]"] {
+        assert_engines_agree(q, doc);
+    }
+}
+
+#[test]
+fn test_boolean_predicates() {
+    let doc = r#"{
+        rows: [
+            { id: 0, a: 1, b: 0, c: 3 },
+            { id: 1, a: 0, b: 2, c: 0 },
+            { id: 2, a: 1, b: 2, c: 3 },
+            { id: 3, a: 0, b: 0, c: 0 },
+            { id: 4, a: 1, b: 2 },
+        ]
+    }"#;
+
+    assert_eq!(query("/rows/*[(a = 1 or b = 2) and c != 3]/id", doc), elements("1"));
+    assert_eq!(query("/rows/*[a = 1 or b = 2 and c != 3]/id", doc), elements("0 1 2 4"));
+    assert_eq!(query("/rows/*[not(a = 1)]/id", doc), elements("1 3"));
+    assert_eq!(query("/rows/*[not(c)]/id", doc), elements("4"));
+    assert_eq!(query("/rows/*[not(a = 1 or b = 2)]/id", doc), elements("3"));
+    assert_eq!(query("/rows/*[not(not(a = 1)) and not(c = 3)]/id", doc), elements("4"));
+    assert_eq!(query("/rows/*[position() > 1 and (a = 0 or not(exists(c)))]/id", doc), elements("3 4"));
+    // the same as chained brackets
+    assert_eq!(query("/rows/*[a = 1 and b = 2]", doc), query("/rows/*[a = 1][b = 2]", doc));
+    assert_eq!(query("/rows/*[a = 1 and b = 2 or c = 0]/id", doc), elements("1 2 3 4"));
+    // inside subpaths
+    assert_eq!(query("/rows[*[a = 0 and b = 0]]/0/id", doc), elements("0"));
+    assert_eq!(query("/rows[not(*[a = 2])]/1/id", doc), elements("1"));
+
+    // `not` of a comparison with a container is true when lenient, but strict mode still fails
+    assert_eq!(query("/rows/*[not(/rows = 1)]", doc).len(), 5);
+    let path = ionpath_parser::path("/rows/*[not(/rows = 1)]").unwrap();
+    assert!(matches!(path.try_match_element(Element::read_one(doc).unwrap(), EvalOptions::new().strict()), Err(EvalError::NonScalarComparison { .. })));
+
+    // every engine agrees
+    let stream = r#"{ a: 1, b: 2 } { a: 2 } { b: { a: 1 } }"#;
+    for q in ["/a[not(= 1)]", "//*[a = 1 and not(b)]", "/*[(= 1 or = 2) and not(= 2)]"] {
+        assert_engines_agree(q, stream);
+    }
+    for q in ["/rows/*[not(a = 1)]/id", "//*[b = 2 and not(c)]"] {
+        assert_engines_agree(q, doc);
+    }
+}
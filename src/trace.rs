@@ -0,0 +1,43 @@
+use ion_rs::element::Element;
+use crate::{Location, Segment};
+
+
+/// What happened while evaluating a single segment of a path.
+///
+/// Every element matching the segment's key (a candidate) either ends up in the output, or is
+/// filtered out by the segment's annotations, by its type filter, or by the first predicate
+/// (`[...]`) it doesn't match.
+#[derive(Debug, Clone, PartialEq)]
+pub struct SegmentEvent<'e> {
+    /// zero-based index of the segment in the path.
+    pub index: usize,
+    pub segment: &'e Segment,
+    /// the number of elements the segment was evaluated against.
+    pub input: usize,
+    /// the number of elements that matched the segment's key.
+    pub candidates: usize,
+    /// candidates without the required annotations.
+    pub filtered_by_annotations: Vec<(Location, &'e Element)>,
+    /// candidates with the required annotations, but not one of the types in `/*:int`.
+    pub filtered_by_type: Vec<(Location, &'e Element)>,
+    /// `filtered_by_predicates[i]` holds the candidates that had the required annotations and
+    /// type, but didn't match the `i`th predicate (and matched all predicates before it).
+    pub filtered_by_predicates: Vec<Vec<(Location, &'e Element)>>,
+    /// the number of candidates that matched, which the next segment is evaluated against.
+    pub output: usize,
+}
+
+/// Receives a [`SegmentEvent`] after each segment of a traced evaluation, e.g. from
+/// [`Path::match_traced`](crate::Path::match_traced).
+///
+/// Only the segments of the traced path itself are reported, not those of predicate subpaths.
+/// Any `FnMut(&SegmentEvent)` closure is a tracer.
+pub trait Tracer {
+    fn segment(&mut self, event: &SegmentEvent);
+}
+
+impl<F: FnMut(&SegmentEvent)> Tracer for F {
+    fn segment(&mut self, event: &SegmentEvent) {
+        self(event)
+    }
+}
@@ -0,0 +1,91 @@
+use ion_rs::element::Element;
+use crate::{CompiledPath, EvalContext, EvalError, EvalOptions, Location, Path};
+use crate::location::Node;
+
+
+/// Several paths whose matches are combined, written `/a/b | /c/d`.
+///
+/// The matches of all paths come back in document order (pre-order, depth-first), and an
+/// element matched by more than one path (or more than once by the same path) is only
+/// returned once.
+///
+/// The paths are compiled once, when the union is created, so a union can be evaluated
+/// repeatedly like a [`CompiledPath`].
+#[derive(Debug, Clone)]
+pub struct PathUnion {
+    paths: Vec<Path>,
+    compiled: Vec<CompiledPath>,
+}
+
+impl PathUnion {
+    pub fn new(paths: Vec<Path>) -> Self {
+        let compiled = paths.iter().map(Path::compile).collect();
+        PathUnion { paths, compiled }
+    }
+
+    pub fn paths(&self) -> &[Path] {
+        &self.paths
+    }
+
+    pub fn match_element(&self, root_element: Element) -> Vec<Element> {
+        self.match_ref(&root_element).into_iter().cloned().collect()
+    }
+
+    /// like [`PathUnion::match_element`], but borrows the root element and returns references
+    /// to the matched elements instead of cloning them.
+    pub fn match_ref<'a>(&self, root_element: &'a Element) -> Vec<&'a Element> {
+        self.match_located(root_element).into_iter().map(|(_, element)| element).collect()
+    }
+
+    /// like [`PathUnion::match_ref`], but also returns where in `root_element` each match
+    /// was found.
+    pub fn match_located<'a>(&self, root_element: &'a Element) -> Vec<(Location, &'a Element)> {
+        let ctx = EvalContext::new(std::slice::from_ref(root_element));
+        // evaluating with the default options never fails
+        self.evaluate(&ctx, &Node::root(root_element, 0)).unwrap_or_default().into_iter()
+            .map(|node| (node.location(), node.element))
+            .collect()
+    }
+
+    /// evaluates every path starting at `element`, or at the context's root(s) for absolute
+    /// paths. the context's options are ignored, see [`PathUnion::try_match_element_in`].
+    pub fn match_element_in<'a>(&self, ctx: &EvalContext<'a>, element: &'a Element) -> Vec<&'a Element> {
+        self.try_match_element_in(&ctx.lenient(), element).unwrap_or_default()
+    }
+
+    /// like [`PathUnion::match_element`], evaluating with the given options.
+    pub fn try_match_element(&self, root_element: Element, options: EvalOptions) -> Result<Vec<Element>, EvalError> {
+        let ctx = EvalContext::new(std::slice::from_ref(&root_element)).with_options(options);
+        Ok(self.try_match_element_in(&ctx, &root_element)?.into_iter().cloned().collect())
+    }
+
+    /// like [`PathUnion::match_element_in`], evaluating with the context's options. the
+    /// limits apply to all paths together.
+    pub fn try_match_element_in<'a>(&self, ctx: &EvalContext<'a>, element: &'a Element) -> Result<Vec<&'a Element>, EvalError> {
+        Ok(self.evaluate(ctx, &Node::root(element, 0))?.into_iter()
+            .map(|node| node.element)
+            .collect())
+    }
+
+    fn evaluate<'a>(&self, ctx: &EvalContext<'a>, node: &Node<'a>) -> Result<Vec<Node<'a>>, EvalError> {
+        let mut found = Vec::new();
+        for path in self.compiled.iter() {
+            found.extend(path.evaluate(ctx, path.start_nodes(ctx, node))?);
+        }
+        Node::document_order(&mut found);
+        Ok(found)
+    }
+}
+
+// the compiled paths follow from the paths
+impl PartialEq for PathUnion {
+    fn eq(&self, other: &Self) -> bool {
+        self.paths == other.paths
+    }
+}
+
+impl From<Path> for PathUnion {
+    fn from(path: Path) -> Self {
+        PathUnion::new(vec![path])
+    }
+}